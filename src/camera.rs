@@ -0,0 +1,156 @@
+use glam::Vec2;
+use speedy2d::color::Color;
+
+use crate::renderer::*;
+
+/*
+ * Everything in the scene lives in world units. The camera decides which part of the world
+ * is on screen, drawing goes through `CameraRenderer` so display code never sees screen pixels.
+ * At zoom 1 a world unit is a screen pixel.
+ * Things further away follow only part of the pan, measured from where the camera starts,
+ * so layered scenes keep their depth while the camera moves.
+ */
+
+/// Area the default scene is laid out in, the camera starts out showing all of it.
+pub const WORLD_SIZE: Vec2 = Vec2::new(1280.0, 720.0);
+pub const MIN_ZOOM: f32 = 0.2;
+pub const MAX_ZOOM: f32 = 8.0;
+/// Where the camera looks at first, layers further away line up with the front there
+const HOME: Vec2 = Vec2::new(WORLD_SIZE.x / 2.0, WORLD_SIZE.y / 2.0);
+
+pub struct Camera2D {
+    /// World point in the middle of the screen
+    pub center: Vec2,
+    /// Screen pixels per world unit
+    pub zoom: f32,
+    /// Screen size in pixels
+    pub viewport: Vec2,
+    /// World point the camera glides towards every update, None stays where it's put
+    pub target: Option<Vec2>,
+    /// How quickly the target is caught up with, per second
+    pub follow_speed: f32,
+}
+
+impl Camera2D {
+    /// Shows the whole default scene on a screen of the same size.
+    pub fn new(viewport: Vec2) -> Self {
+        Camera2D { center: HOME, zoom: 1.0, viewport, target: None, follow_speed: 3.0 }
+    }
+
+    pub fn world_to_screen(&self, point: Vec2) -> Vec2 {
+        self.world_to_screen_at(point, 1.0)
+    }
+
+    pub fn screen_to_world(&self, point: Vec2) -> Vec2 {
+        self.screen_to_world_at(point, 1.0)
+    }
+
+    /// Center as seen by something that only moves `depth_scale` as far as the front when panned.
+    fn get_center_at(&self, depth_scale: f32) -> Vec2 {
+        HOME + (self.center - HOME) * depth_scale
+    }
+
+    /// Like `world_to_screen` for something at a depth with the given perspective scale.
+    pub fn world_to_screen_at(&self, point: Vec2, depth_scale: f32) -> Vec2 {
+        (point - self.get_center_at(depth_scale)) * self.zoom + self.viewport / 2.0
+    }
+
+    pub fn screen_to_world_at(&self, point: Vec2, depth_scale: f32) -> Vec2 {
+        (point - self.viewport / 2.0) / self.zoom + self.get_center_at(depth_scale)
+    }
+
+    /// Top left and bottom right corners of what's on screen, in world units.
+    pub fn get_visible_area(&self) -> (Vec2, Vec2) {
+        (self.screen_to_world(Vec2::ZERO), self.screen_to_world(self.viewport))
+    }
+
+    /// Drags the world along with the cursor, `delta` in screen pixels.
+    pub fn pan(&mut self, delta: Vec2) {
+        self.center -= delta / self.zoom;
+        self.target = None;
+    }
+
+    /// Zooms by `factor`, keeping the world point under `screen_point` where it is.
+    pub fn zoom_at(&mut self, screen_point: Vec2, factor: f32) {
+        let anchor = self.screen_to_world(screen_point);
+        self.zoom = (self.zoom * factor).clamp(MIN_ZOOM, MAX_ZOOM);
+        self.center = anchor - (screen_point - self.viewport / 2.0) / self.zoom;
+    }
+
+    pub fn follow(&mut self, target: Vec2) {
+        self.target = Some(target);
+    }
+
+    /// Glides towards the target, the same speed no matter the frame rate.
+    pub fn update(&mut self, dt: f32) {
+        if let Some(target) = self.target {
+            self.center += (target - self.center) * (1.0 - (-self.follow_speed * dt).exp());
+        }
+    }
+}
+
+/// Draws world shapes where the camera sees them.
+pub struct CameraRenderer<'a> {
+    pub renderer: &'a mut dyn Renderer,
+    pub camera: &'a Camera2D,
+    /// Perspective scale of what's being drawn, 1 for the front, less moves less when panning
+    pub depth_scale: f32,
+}
+
+impl<'a> CameraRenderer<'a> {
+    pub fn new(renderer: &'a mut dyn Renderer, camera: &'a Camera2D) -> Self {
+        CameraRenderer { renderer, camera, depth_scale: 1.0 }
+    }
+
+    fn to_screen(&self, point: Vec2) -> Vec2 {
+        self.camera.world_to_screen_at(point, self.depth_scale)
+    }
+}
+
+impl Renderer for CameraRenderer<'_> {
+    fn clear(&mut self, color: Color) {
+        self.renderer.clear(color);
+    }
+
+    fn draw_circle(&mut self, center: Vec2, radius: f32, color: Color) {
+        self.renderer.draw_circle(self.to_screen(center), radius * self.camera.zoom, color);
+    }
+
+    fn draw_line(&mut self, start: Vec2, end: Vec2, thickness: f32, color: Color) {
+        let (start, end) = (self.to_screen(start), self.to_screen(end));
+        self.renderer.draw_line(start, end, thickness * self.camera.zoom, color);
+    }
+
+    fn draw_quad(&mut self, corners: [Vec2; 4], colors: [Color; 4]) {
+        let corners = corners.map(|p| self.to_screen(p));
+        self.renderer.draw_quad(corners, colors);
+    }
+
+    fn draw_textured_quad(&mut self, corners: [Vec2; 4], colors: [Color; 4], image_coords: [Vec2; 4], texture: &Texture, blend: BlendMode) {
+        let corners = corners.map(|p| self.to_screen(p));
+        self.renderer.draw_textured_quad(corners, colors, image_coords, texture, blend);
+    }
+
+    fn get_visible_area(&self) -> (Vec2, Vec2) {
+        let camera = self.camera;
+        (camera.screen_to_world_at(Vec2::ZERO, self.depth_scale), camera.screen_to_world_at(camera.viewport, self.depth_scale))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn far_layers_follow_less_of_the_pan() {
+        let mut camera = Camera2D::new(WORLD_SIZE);
+        let point = Vec2::new(300.0, 200.0);
+        assert_eq!(camera.world_to_screen_at(point, 0.5), camera.world_to_screen(point));
+
+        camera.pan(Vec2::new(-100.0, 40.0));
+        assert_eq!(camera.world_to_screen(point) - point, Vec2::new(-100.0, 40.0));
+        assert_eq!(camera.world_to_screen_at(point, 0.5) - point, Vec2::new(-50.0, 20.0));
+        let back = camera.screen_to_world_at(camera.world_to_screen_at(point, 0.5), 0.5);
+        assert!(back.distance(point) < 1e-3);
+    }
+}
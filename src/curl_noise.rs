@@ -0,0 +1,84 @@
+use glam::{Vec2, Vec3};
+
+use crate::easing::*;
+
+/*
+ * Curl noise: take smooth gradient noise as a stream function and use its curl as velocity.
+ * The field has no divergence, so whatever it carries swirls around instead of bunching up.
+ * Time is the third noise axis, moving along it makes the swirls evolve.
+ */
+
+/// Small epsilon for the finite differences, in noise space.
+const EPSILON: f32 = 0.01;
+
+#[derive(Clone, Copy)]
+pub struct CurlNoise {
+    /// Size of one swirl in pixels
+    pub scale: f32,
+    /// Typical speed things are carried at, pixels per second, peaks are about twice that
+    pub strength: f32,
+    /// How fast the swirls change, noise cells per second
+    pub evolution: f32,
+    pub seed: u32,
+    /// Particles carried by the field sample it once every this many frames and keep
+    /// the last velocity in between, sampling is most of the cost with lots of particles
+    pub refresh: usize,
+}
+
+impl Default for CurlNoise {
+    fn default() -> Self {
+        CurlNoise { scale: 150.0, strength: 60.0, evolution: 0.3, seed: 0, refresh: 4 }
+    }
+}
+
+fn hash(x: i32, y: i32, z: i32, seed: u32) -> u32 {
+    let mut h = seed ^ (x as u32).wrapping_mul(0x8da6b343) ^ (y as u32).wrapping_mul(0xd8163841) ^ (z as u32).wrapping_mul(0xcb1ab31f);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x2c1b3c6d);
+    h ^= h >> 12;
+    h = h.wrapping_mul(0x297a2d39);
+    h ^ (h >> 15)
+}
+
+/// One of the 12 cube edge directions, the usual Perlin gradients.
+fn gradient(hash: u32) -> Vec3 {
+    const GRADIENTS: [Vec3; 12] = [
+        Vec3::new(1.0, 1.0, 0.0), Vec3::new(-1.0, 1.0, 0.0), Vec3::new(1.0, -1.0, 0.0), Vec3::new(-1.0, -1.0, 0.0),
+        Vec3::new(1.0, 0.0, 1.0), Vec3::new(-1.0, 0.0, 1.0), Vec3::new(1.0, 0.0, -1.0), Vec3::new(-1.0, 0.0, -1.0),
+        Vec3::new(0.0, 1.0, 1.0), Vec3::new(0.0, -1.0, 1.0), Vec3::new(0.0, 1.0, -1.0), Vec3::new(0.0, -1.0, -1.0),
+    ];
+    GRADIENTS[(hash % 12) as usize]
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+/// 3D Perlin noise, roughly in -1..1.
+pub fn perlin(point: Vec3, seed: u32) -> f32 {
+    let cell = point.floor();
+    let local = point - cell;
+    let (x, y, z) = (cell.x as i32, cell.y as i32, cell.z as i32);
+
+    let corner = |dx: i32, dy: i32, dz: i32| {
+        let offset = local - Vec3::new(dx as f32, dy as f32, dz as f32);
+        gradient(hash(x + dx, y + dy, z + dz, seed)).dot(offset)
+    };
+
+    let (u, v, w) = (fade(local.x), fade(local.y), fade(local.z));
+    let near = lerp(lerp(corner(0, 0, 0), corner(1, 0, 0), u), lerp(corner(0, 1, 0), corner(1, 1, 0), u), v);
+    let far = lerp(lerp(corner(0, 0, 1), corner(1, 0, 1), u), lerp(corner(0, 1, 1), corner(1, 1, 1), u), v);
+    lerp(near, far, w)
+}
+
+impl CurlNoise {
+    /// Velocity of the field at the point, in pixels per second.
+    pub fn get_velocity(&self, point: Vec2, time: f32) -> Vec2 {
+        let at = (point / self.scale).extend(time * self.evolution);
+        let potential = |offset: Vec2| perlin(at + offset.extend(0.0), self.seed);
+
+        let d_dx = (potential(Vec2::new(EPSILON, 0.0)) - potential(Vec2::new(-EPSILON, 0.0))) / (2.0 * EPSILON);
+        let d_dy = (potential(Vec2::new(0.0, EPSILON)) - potential(Vec2::new(0.0, -EPSILON))) / (2.0 * EPSILON);
+        Vec2::new(d_dy, -d_dx) * self.strength
+    }
+}
@@ -0,0 +1,104 @@
+use glam::Vec2;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cell::RefCell;
+use std::f32::consts::PI;
+
+/*
+ * Random values shared by everything that generates things.
+ * One generator per thread, seed it to get the same trees and smoke every run.
+ */
+
+thread_local! {
+    static RNG: RefCell<StdRng> = RefCell::new(StdRng::from_entropy());
+}
+
+/// Makes everything drawn from here after this call repeat between runs.
+pub fn seed_rng(seed: u64) {
+    RNG.with(|rng| *rng.borrow_mut() = StdRng::seed_from_u64(seed));
+}
+
+fn with_rng<T>(f: impl FnOnce(&mut StdRng) -> T) -> T {
+    RNG.with(|rng| f(&mut rng.borrow_mut()))
+}
+
+/// Uniform in 0..1.
+pub fn unit() -> f32 {
+    with_rng(|rng| rng.gen())
+}
+
+/// Uniform between the two, which can come in any order or be equal.
+pub fn uniform(a: f32, b: f32) -> f32 {
+    a + (b - a) * unit()
+}
+
+/// Gaussian around the mean, Box-Muller.
+pub fn normal(mean: f32, std_dev: f32) -> f32 {
+    let u = 1.0 - unit(); // Keeps the log away from zero
+    let v = unit();
+    mean + std_dev * (-2.0 * u.ln()).sqrt() * (2.0 * PI * v).cos()
+}
+
+/// Between min and max, half of the values land below `min + (max - min) * bias`.
+/// 0.5 is uniform, smaller bias crowds values towards min.
+pub fn biased_range(min: f32, max: f32, bias: f32) -> f32 {
+    let exponent = bias.clamp(0.001, 0.999).ln() / 0.5f32.ln();
+    min + (max - min) * unit().powf(exponent)
+}
+
+pub fn chance(probability: f32) -> bool {
+    unit() < probability
+}
+
+/// 1 or -1, even odds.
+pub fn sign() -> f32 {
+    if chance(0.5) { 1.0 } else { -1.0 }
+}
+
+pub fn angle() -> f32 {
+    uniform(-PI, PI)
+}
+
+/// Uniform over the area of a disk around zero.
+pub fn on_disk(radius: f32) -> Vec2 {
+    // Square root keeps the points evenly spread instead of crowding the middle
+    Vec2::from_angle(angle()) * radius * unit().sqrt()
+}
+
+/// Uniform on the edge of a circle around zero.
+pub fn on_circle(radius: f32) -> Vec2 {
+    Vec2::from_angle(angle()) * radius
+}
+
+/// Picks an item with probability proportional to its weight, items weighing 0 or less never come up.
+/// None when nothing has any weight.
+pub fn weighted_choice<T>(items: &[(T, f32)]) -> Option<&T> {
+    let weighted = || items.iter().filter(|(_, weight)| *weight > 0.0);
+    let total: f32 = weighted().map(|(_, weight)| weight).sum();
+    if !total.is_finite() || total <= 0.0 {
+        return None;
+    }
+    let mut choice = uniform(0.0, total);
+    for (item, weight) in weighted() {
+        if choice < *weight {
+            return Some(item);
+        }
+        choice -= weight;
+    }
+    weighted().last().map(|(item, _)| item)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weighted_choice_skips_weightless_items() {
+        let empty: [(u8, f32); 0] = [];
+        assert_eq!(weighted_choice(&empty), None);
+        assert_eq!(weighted_choice(&[(1, 0.0), (2, -1.0)]), None);
+        for _ in 0..100 {
+            assert_eq!(weighted_choice(&[(1, 0.0), (2, 1.0), (3, -5.0), (4, f32::NAN)]), Some(&2));
+        }
+    }
+}
@@ -0,0 +1,109 @@
+/*
+ * Easing curves map t in 0..1 to how far along the way from a to b we are.
+ * Most start at 0 and end at 1, bezier and keyframes are free to overshoot.
+ */
+
+#[derive(Clone)]
+pub enum Easing {
+    Linear,
+    /// Slow start, power says how slow
+    SmoothStart(f32),
+    /// Slow end, power says how slow
+    SmoothStop(f32),
+    SmoothStep,
+    /// Cubic bezier from (0, 0) to (1, 1) with control points (x1, y1) and (x2, y2), like css
+    Bezier(f32, f32, f32, f32),
+    /// (t, value) keys sorted by t, linear in between
+    Keyframes(Vec<(f32, f32)>),
+}
+
+pub fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+fn cubic_bezier(p1: f32, p2: f32, t: f32) -> f32 {
+    let u = 1.0 - t;
+    3.0 * u * u * t * p1 + 3.0 * u * t * t * p2 + t * t * t
+}
+
+/// Finds t where the x of the curve is `x`, x only ever grows since control x values are in 0..1.
+fn solve_bezier_x(x1: f32, x2: f32, x: f32) -> f32 {
+    let (mut low, mut high) = (0.0, 1.0);
+    for _ in 0..24 {
+        let mid = (low + high) / 2.0;
+        if cubic_bezier(x1, x2, mid) < x {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    (low + high) / 2.0
+}
+
+/// Finds the keys around `t` in (t, value) keys sorted by t: the index of the one before it and
+/// how far towards the next one it is. Flat past the ends, None without any keys.
+pub fn find_key<T>(keys: &[(f32, T)], t: f32) -> Option<(usize, f32)> {
+    let first_t = keys.first()?.0;
+    if t <= first_t {
+        return Some((0, 0.0));
+    }
+    for (i, pair) in keys.windows(2).enumerate() {
+        let (t0, t1) = (pair[0].0, pair[1].0);
+        if t <= t1 {
+            return Some((i, (t - t0) / (t1 - t0).max(f32::EPSILON)));
+        }
+    }
+    Some((keys.len() - 1, 0.0))
+}
+
+/// Value at `t` between (t, value) keys sorted by t, linear in between and flat past the ends.
+pub fn keyframes(keys: &[(f32, f32)], t: f32) -> f32 {
+    let Some((i, local_t)) = find_key(keys, t) else { return t };
+    lerp(keys[i].1, keys[(i + 1).min(keys.len() - 1)].1, local_t)
+}
+
+impl Easing {
+    pub fn apply(&self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Easing::Linear => t,
+            Easing::SmoothStart(power) => t.powf(*power),
+            Easing::SmoothStop(power) => 1.0 - (1.0 - t).powf(*power),
+            Easing::SmoothStep => t * t * (3.0 - 2.0 * t),
+            Easing::Bezier(x1, y1, x2, y2) => {
+                let x1 = x1.clamp(0.0, 1.0);
+                let x2 = x2.clamp(0.0, 1.0);
+                cubic_bezier(*y1, *y2, solve_bezier_x(x1, x2, t))
+            },
+            Easing::Keyframes(keys) => keyframes(keys, t),
+        }
+    }
+
+    pub fn interpolate(&self, a: f32, b: f32, t: f32) -> f32 {
+        a + (b - a) * self.apply(t)
+    }
+}
+
+impl Default for Easing {
+    fn default() -> Self {
+        Easing::SmoothStop(2.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_found_and_flat_past_the_ends() {
+        let keys = [(0.25, 1.0), (0.75, 3.0), (1.0, 0.0)];
+        assert_eq!(find_key(&keys, 0.0), Some((0, 0.0)));
+        assert_eq!(find_key(&keys, 0.5), Some((0, 0.5)));
+        assert_eq!(find_key(&keys, 0.875), Some((1, 0.5)));
+        assert_eq!(find_key(&keys, 2.0), Some((2, 0.0)));
+        assert_eq!(find_key::<f32>(&[], 0.5), None);
+        assert_eq!(keyframes(&keys, 0.5), 2.0);
+        assert_eq!(keyframes(&keys, 2.0), 0.0);
+        assert_eq!(keyframes(&[(0.5, 7.0)], 0.9), 7.0);
+    }
+}
@@ -0,0 +1,72 @@
+use glam::Vec2;
+
+use crate::verlet_physics::*;
+use crate::tree_gen::*;
+
+/// Branch segment of another tree, growing branches keep away from it.
+struct Obstacle {
+    start: Vec2,
+    end: Vec2,
+    width: f32,
+}
+
+/// Surroundings a tree grows in.
+pub struct Environment {
+    /// Direction towards the light, branches bend to it
+    pub light_direction: Vec2,
+    pub colliders: Vec<Collider>,
+    obstacles: Vec<Obstacle>,
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Environment { light_direction: Vec2::NEG_Y, colliders: Vec::new(), obstacles: Vec::new() }
+    }
+}
+
+fn get_segment_distance(point: Vec2, start: Vec2, end: Vec2) -> (f32, Vec2) {
+    let segment = end - start;
+    let t = if segment == Vec2::ZERO { 0.0 } else { ((point - start).dot(segment) / segment.length_squared()).clamp(0.0, 1.0) };
+    let diff = point - (start + segment * t);
+    let normal = if diff == Vec2::ZERO { segment.perp().normalize_or_zero() } else { diff.normalize() };
+    (diff.length(), normal)
+}
+
+impl Environment {
+    pub fn new(light_direction: Vec2) -> Self {
+        Environment { light_direction: light_direction.normalize_or_zero(), ..Default::default() }
+    }
+
+    /// Makes trees grown later keep away from the branches of this one.
+    pub fn add_tree(&mut self, tree: &Tree) {
+        for (start, end, width) in tree.get_segments() {
+            self.obstacles.push(Obstacle { start, end, width });
+        }
+    }
+
+    /// Distance to every collider and obstacle, with the direction away from it.
+    fn get_distances(&self, point: Vec2) -> impl Iterator<Item = (f32, Vec2)> + '_ {
+        let colliders = self.colliders.iter().map(move |collider| collider.get_distance(point));
+        let obstacles = self.obstacles.iter().map(move |obstacle| {
+            let (distance, normal) = get_segment_distance(point, obstacle.start, obstacle.end);
+            (distance - obstacle.width / 2.0, normal)
+        });
+        colliders.chain(obstacles)
+    }
+
+    /// Is there anything within `clearance` of the point.
+    pub fn is_blocked(&self, point: Vec2, clearance: f32) -> bool {
+        self.get_distances(point).any(|(distance, _)| distance < clearance)
+    }
+
+    /// Push away from everything closer than `range`, stronger the closer it is.
+    pub fn get_repulsion(&self, point: Vec2, range: f32) -> Vec2 {
+        let mut repulsion = Vec2::ZERO;
+        for (distance, normal) in self.get_distances(point) {
+            if distance < range {
+                repulsion += normal * (1.0 - distance.max(0.0) / range);
+            }
+        }
+        repulsion
+    }
+}
@@ -0,0 +1,198 @@
+use glam::Vec2;
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::{Delay, Frame, RgbaImage};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+use crate::renderer::*;
+use crate::software_renderer::*;
+
+/*
+ * Renders a scene frame by frame on the CPU at a fixed timestep, so clips come out smooth
+ * no matter how long each frame takes to draw.
+ *
+ *     export <output> [--format frames|gif|apng] [--size 1920x1080] [--frames 60..360]
+ *                     [--fps 60] [--supersample 2] [--seed 1]
+ *
+ * Without --format, `.gif` makes a GIF, `.png` or `.apng` an animated PNG,
+ * anything else a folder of numbered PNG frames.
+ */
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ExportFormat {
+    /// Numbered PNG files in a folder
+    Frames,
+    Gif,
+    Apng,
+}
+
+#[derive(Debug)]
+pub struct ExportConfig {
+    pub output: PathBuf,
+    pub format: ExportFormat,
+    pub width: u32,
+    pub height: u32,
+    /// Frames before this one are simulated but not saved, lets the scene settle first
+    pub first_frame: u32,
+    /// One past the last saved frame
+    pub end_frame: u32,
+    pub fps: u32,
+    /// Draws this many times bigger on each side and averages down, smooths edges
+    pub supersampling: u32,
+    pub seed: Option<u64>,
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        ExportConfig {
+            output: PathBuf::from("export"),
+            format: ExportFormat::Frames,
+            width: 1280,
+            height: 720,
+            first_frame: 0,
+            end_frame: 300,
+            fps: 60,
+            supersampling: 2,
+            seed: None,
+        }
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str, option: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("{option}: can't read '{value}' as a number"))
+}
+
+fn parse_pair<T: std::str::FromStr>(value: &str, separator: &str, option: &str) -> Result<(T, T), String> {
+    let (a, b) = value.split_once(separator).ok_or(format!("{option}: expected two numbers separated by '{separator}', got '{value}'"))?;
+    Ok((parse_number(a, option)?, parse_number(b, option)?))
+}
+
+fn guess_format(output: &std::path::Path) -> ExportFormat {
+    match output.extension().and_then(|e| e.to_str()) {
+        Some("gif") => ExportFormat::Gif,
+        Some("png") | Some("apng") => ExportFormat::Apng,
+        _ => ExportFormat::Frames,
+    }
+}
+
+impl ExportConfig {
+    /// Reads the arguments after `export`.
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let mut args = args.iter();
+        let output = PathBuf::from(args.next().ok_or("export needs an output path")?);
+        let mut config = ExportConfig { format: guess_format(&output), output, ..Default::default() };
+        while let Some(option) = args.next() {
+            let value = args.next().ok_or(format!("{option} needs a value"))?;
+            match option.as_str() {
+                "--format" => config.format = match value.as_str() {
+                    "frames" => ExportFormat::Frames,
+                    "gif" => ExportFormat::Gif,
+                    "apng" => ExportFormat::Apng,
+                    _ => return Err(format!("unknown format '{value}', expected frames, gif or apng")),
+                },
+                "--size" => (config.width, config.height) = parse_pair(value, "x", option)?,
+                "--frames" => (config.first_frame, config.end_frame) = parse_pair(value, "..", option)?,
+                "--fps" => config.fps = parse_number(value, option)?,
+                "--supersample" => config.supersampling = parse_number(value, option)?,
+                "--seed" => config.seed = Some(parse_number(value, option)?),
+                _ => return Err(format!("unknown option {option}")),
+            }
+        }
+        if config.width == 0 || config.height == 0 || config.fps == 0 || config.supersampling == 0 {
+            return Err("size, fps and supersampling have to be above zero".to_string());
+        }
+        if config.first_frame >= config.end_frame {
+            return Err(format!("frame range {}..{} is empty", config.first_frame, config.end_frame));
+        }
+        Ok(config)
+    }
+
+    /// Seconds between frames, scenes have to step their physics by this too or clips
+    /// not at 60 fps play at the wrong speed.
+    pub fn get_timestep(&self) -> f32 {
+        1.0 / self.fps as f32
+    }
+}
+
+/// Where finished frames go.
+enum FrameWriter {
+    Frames(PathBuf),
+    Gif(Box<GifEncoder<BufWriter<File>>>),
+    Apng(png::Writer<BufWriter<File>>),
+}
+
+impl FrameWriter {
+    fn new(config: &ExportConfig) -> Result<Self, String> {
+        let create = || File::create(&config.output).map(BufWriter::new).map_err(|e| e.to_string());
+        match config.format {
+            ExportFormat::Frames => {
+                std::fs::create_dir_all(&config.output).map_err(|e| e.to_string())?;
+                Ok(FrameWriter::Frames(config.output.clone()))
+            },
+            ExportFormat::Gif => {
+                // Faster color quantization than the default, barely visible on smoke
+                const GIF_SPEED: i32 = 10;
+                let mut encoder = GifEncoder::new_with_speed(create()?, GIF_SPEED);
+                encoder.set_repeat(Repeat::Infinite).map_err(|e| e.to_string())?;
+                Ok(FrameWriter::Gif(Box::new(encoder)))
+            },
+            ExportFormat::Apng => {
+                let mut encoder = png::Encoder::new(create()?, config.width, config.height);
+                encoder.set_color(png::ColorType::Rgba);
+                encoder.set_depth(png::BitDepth::Eight);
+                encoder.set_animated(config.end_frame - config.first_frame, 0).map_err(|e| e.to_string())?;
+                let fps = u16::try_from(config.fps).map_err(|_| "fps is too high for APNG")?;
+                encoder.set_frame_delay(1, fps).map_err(|e| e.to_string())?;
+                Ok(FrameWriter::Apng(encoder.write_header().map_err(|e| e.to_string())?))
+            },
+        }
+    }
+
+    fn write(&mut self, config: &ExportConfig, index: u32, pixels: Vec<u8>) -> Result<(), String> {
+        match self {
+            FrameWriter::Frames(folder) => {
+                let path = folder.join(format!("frame_{index:05}.png"));
+                image::save_buffer(path, &pixels, config.width, config.height, image::ColorType::Rgba8).map_err(|e| e.to_string())
+            },
+            FrameWriter::Gif(encoder) => {
+                let image = RgbaImage::from_raw(config.width, config.height, pixels).unwrap();
+                let delay = Delay::from_numer_denom_ms(1000, config.fps);
+                encoder.encode_frame(Frame::from_parts(image, 0, 0, delay)).map_err(|e| e.to_string())
+            },
+            FrameWriter::Apng(writer) => writer.write_image_data(&pixels).map_err(|e| e.to_string()),
+        }
+    }
+
+    fn finish(self) -> Result<(), String> {
+        match self {
+            FrameWriter::Apng(writer) => writer.finish().map_err(|e| e.to_string()),
+            // GIF gets its trailer when dropped, frames are already on disk
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Steps the scene from frame 0 to the end of the range, saving the frames inside it.
+/// `scene_size` is the part of the scene that gets fit into the picture, `draw_frame` moves
+/// the scene along by the timestep and draws it.
+pub fn export(config: &ExportConfig, scene_size: Vec2, mut draw_frame: impl FnMut(&mut dyn Renderer, f32)) -> Result<(), String> {
+    let dt = config.get_timestep();
+    let scale = (config.width as f32 / scene_size.x).min(config.height as f32 / scene_size.y) * config.supersampling as f32;
+    let (width, height) = (config.width * config.supersampling, config.height * config.supersampling);
+    let mut writer = FrameWriter::new(config)?;
+
+    // Nothing to draw into, frames before the range only move the scene along
+    let mut skipped = SoftwareRenderer::new(0, 0);
+    for _ in 0..config.first_frame {
+        draw_frame(&mut skipped, dt);
+    }
+
+    let mut renderer = SoftwareRenderer::with_scale(width, height, scale);
+    for index in config.first_frame..config.end_frame {
+        draw_frame(&mut renderer, dt);
+        writer.write(config, index, renderer.to_rgba8_downsampled(config.supersampling))?;
+        println!("frame {} / {}", index + 1 - config.first_frame, config.end_frame - config.first_frame);
+    }
+    writer.finish()
+}
@@ -0,0 +1,326 @@
+use glam::Vec2;
+use speedy2d::color::Color;
+
+use crate::verlet_physics::*;
+use crate::renderer::*;
+
+/*
+ * Stable fluids (Stam 1999) on a grid of square cells.
+ * Every step: forces -> diffuse -> project -> advect -> project for velocity,
+ * then diffuse -> advect for density and temperature.
+ * Velocity is in pixels per second, cells inside colliders are solid and nothing flows through them.
+ */
+
+pub struct FluidConfig {
+    /// World position of the top left corner of the grid
+    pub origin: Vec2,
+    pub width: usize,
+    pub height: usize,
+    /// Pixels per cell
+    pub cell_size: f32,
+    pub viscosity: f32,
+    pub diffusion: f32,
+    /// Upward push per degree above ambient, pixels per second squared
+    pub buoyancy: f32,
+    /// Downward pull of dense smoke
+    pub weight: f32,
+    /// Fraction of heat lost per second
+    pub cooling: f32,
+    /// Fraction of density lost per second
+    pub dissipation: f32,
+    /// Gauss-Seidel iterations for diffusion and pressure
+    pub iterations: usize,
+}
+
+impl Default for FluidConfig {
+    fn default() -> Self {
+        FluidConfig {
+            origin: Vec2::ZERO,
+            width: 128,
+            height: 72,
+            cell_size: 10.0,
+            viscosity: 0.0,
+            diffusion: 0.0,
+            buoyancy: 40.0,
+            weight: 5.0,
+            cooling: 0.5,
+            dissipation: 0.2,
+            iterations: 20,
+        }
+    }
+}
+
+pub struct FluidGrid {
+    pub config: FluidConfig,
+    velocity_x: Vec<f32>,
+    velocity_y: Vec<f32>,
+    density: Vec<f32>,
+    /// Degrees above ambient
+    temperature: Vec<f32>,
+    solid: Vec<bool>,
+    /// Scratch space so steps don't allocate
+    scratch: Vec<f32>,
+    previous_x: Vec<f32>,
+    previous_y: Vec<f32>,
+    divergence: Vec<f32>,
+    pressure: Vec<f32>,
+    /// Density drawn as an image, refreshed after every step
+    texture: Texture,
+    /// Is any of the density dense enough to show up
+    visible: bool,
+}
+
+/// Which field a boundary is set for, velocities bounce off walls, the rest just copies over.
+#[derive(Clone, Copy, PartialEq)]
+enum Boundary {
+    Scalar,
+    VelocityX,
+    VelocityY,
+}
+
+impl FluidGrid {
+    pub fn new(config: FluidConfig) -> Self {
+        let cells = config.width * config.height;
+        let texture = Texture::from_rgba(config.width as u32, config.height as u32, vec![0; cells * 4]);
+        FluidGrid {
+            config,
+            velocity_x: vec![0.0; cells],
+            velocity_y: vec![0.0; cells],
+            density: vec![0.0; cells],
+            temperature: vec![0.0; cells],
+            solid: vec![false; cells],
+            scratch: vec![0.0; cells],
+            previous_x: vec![0.0; cells],
+            previous_y: vec![0.0; cells],
+            divergence: vec![0.0; cells],
+            pressure: vec![0.0; cells],
+            texture,
+            visible: false,
+        }
+    }
+
+    fn index(&self, x: usize, y: usize) -> usize {
+        y * self.config.width + x
+    }
+
+    /// Position in cells, cell centers are at whole numbers.
+    fn to_grid(&self, point: Vec2) -> Vec2 {
+        (point - self.config.origin) / self.config.cell_size - 0.5
+    }
+
+    /// Marks cells inside the colliders as solid.
+    pub fn set_obstacles(&mut self, colliders: &[Collider]) {
+        for y in 0..self.config.height {
+            for x in 0..self.config.width {
+                let center = self.config.origin + (Vec2::new(x as f32, y as f32) + 0.5) * self.config.cell_size;
+                let i = self.index(x, y);
+                self.solid[i] = colliders.iter().any(|collider| collider.get_distance(center).0 < 0.0);
+            }
+        }
+    }
+
+    /// Pours hot smoke into a circle, pushing it with `velocity`.
+    pub fn add_source(&mut self, center: Vec2, radius: f32, density: f32, temperature: f32, velocity: Vec2) {
+        let grid_center = self.to_grid(center);
+        let grid_radius = radius / self.config.cell_size;
+        for y in 0..self.config.height {
+            for x in 0..self.config.width {
+                let i = self.index(x, y);
+                if self.solid[i] || Vec2::new(x as f32, y as f32).distance(grid_center) > grid_radius {
+                    continue;
+                }
+                self.density[i] += density;
+                self.temperature[i] += temperature;
+                self.velocity_x[i] = velocity.x;
+                self.velocity_y[i] = velocity.y;
+            }
+        }
+    }
+
+    pub fn step(&mut self, dt: f32) {
+        self.add_buoyancy(dt);
+
+        let viscosity = self.config.viscosity;
+        Self::diffuse(&self.config, &self.solid, &mut self.velocity_x, &mut self.scratch, viscosity, dt, Boundary::VelocityX);
+        Self::diffuse(&self.config, &self.solid, &mut self.velocity_y, &mut self.scratch, viscosity, dt, Boundary::VelocityY);
+        self.project();
+
+        self.previous_x.copy_from_slice(&self.velocity_x);
+        self.previous_y.copy_from_slice(&self.velocity_y);
+        Self::advect(&self.config, &self.solid, &mut self.velocity_x, &mut self.scratch, &self.previous_x, &self.previous_y, dt, Boundary::VelocityX);
+        Self::advect(&self.config, &self.solid, &mut self.velocity_y, &mut self.scratch, &self.previous_x, &self.previous_y, dt, Boundary::VelocityY);
+        self.project();
+
+        let diffusion = self.config.diffusion;
+        for field in [&mut self.density, &mut self.temperature] {
+            Self::diffuse(&self.config, &self.solid, field, &mut self.scratch, diffusion, dt, Boundary::Scalar);
+            Self::advect(&self.config, &self.solid, field, &mut self.scratch, &self.velocity_x, &self.velocity_y, dt, Boundary::Scalar);
+        }
+
+        let dissipation = (-self.config.dissipation * dt).exp();
+        let cooling = (-self.config.cooling * dt).exp();
+        self.density.iter_mut().for_each(|density| *density *= dissipation);
+        self.temperature.iter_mut().for_each(|temperature| *temperature *= cooling);
+        self.update_texture();
+    }
+
+    /// Writes the density into the texture as grey, only when there's something to see.
+    fn update_texture(&mut self) {
+        self.visible = self.density.iter().any(|&density| density >= 1.0 / 255.0);
+        if !self.visible {
+            return;
+        }
+        let pixels = self.texture.pixels_mut();
+        for ((pixel, &density), &temperature) in pixels.chunks_exact_mut(4).zip(&self.density).zip(&self.temperature) {
+            // Hot smoke glows a little
+            let glow = (temperature * 0.5).clamp(0.0, 1.0);
+            let alpha = density.clamp(0.0, 1.0);
+            pixel.copy_from_slice(&[(200.0 + 55.0 * glow) as u8, (200.0 + 20.0 * glow) as u8, (200.0 - 60.0 * glow) as u8, (alpha * 255.0) as u8]);
+        }
+    }
+
+    fn add_buoyancy(&mut self, dt: f32) {
+        for i in 0..self.density.len() {
+            let lift = self.config.buoyancy * self.temperature[i] - self.config.weight * self.density[i];
+            self.velocity_y[i] -= lift * dt;
+        }
+    }
+
+    fn set_boundary(config: &FluidConfig, solid: &[bool], field: &mut [f32], boundary: Boundary) {
+        let (width, height) = (config.width, config.height);
+        let is_edge = |x: usize, y: usize| x == 0 || y == 0 || x == width - 1 || y == height - 1;
+        for y in 0..height {
+            for x in 0..width {
+                let i = y * width + x;
+                let edge = is_edge(x, y);
+                if !solid[i] && !edge {
+                    continue;
+                }
+                let corner = (x == 0 || x == width - 1) && (y == 0 || y == height - 1);
+                // Walls take the value of open neighbours, velocity into the wall is mirrored.
+                // Grid edges only look inwards, corners at the edges next to them.
+                let mut sum = 0.0;
+                let mut count = 0;
+                for (dx, dy) in [(-1, 0), (1, 0), (0, -1), (0, 1)] {
+                    let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+                    if nx < 0 || ny < 0 || nx >= width as i32 || ny >= height as i32 {
+                        continue;
+                    }
+                    let (nx, ny) = (nx as usize, ny as usize);
+                    let n = ny * width + nx;
+                    if solid[n] || (edge && !corner && is_edge(nx, ny)) {
+                        continue;
+                    }
+                    let mirrored = !corner && ((boundary == Boundary::VelocityX && dx != 0) || (boundary == Boundary::VelocityY && dy != 0));
+                    sum += if mirrored { -field[n] } else { field[n] };
+                    count += 1;
+                }
+                field[i] = if count > 0 { sum / count as f32 } else { 0.0 };
+            }
+        }
+    }
+
+    /// Gauss-Seidel relaxation of x - a * laplacian(x) = x0.
+    fn solve(config: &FluidConfig, solid: &[bool], field: &mut [f32], initial: &[f32], a: f32, c: f32, boundary: Boundary) {
+        let width = config.width;
+        for _ in 0..config.iterations {
+            for y in 1..config.height - 1 {
+                for x in 1..width - 1 {
+                    let i = y * width + x;
+                    if solid[i] { continue; }
+                    let neighbours = field[i - 1] + field[i + 1] + field[i - width] + field[i + width];
+                    field[i] = (initial[i] + a * neighbours) / c;
+                }
+            }
+            Self::set_boundary(config, solid, field, boundary);
+        }
+    }
+
+    fn diffuse(config: &FluidConfig, solid: &[bool], field: &mut [f32], scratch: &mut [f32], rate: f32, dt: f32, boundary: Boundary) {
+        if rate == 0.0 { return; }
+        let a = dt * rate / (config.cell_size * config.cell_size);
+        scratch.copy_from_slice(field);
+        Self::solve(config, solid, field, scratch, a, 1.0 + 4.0 * a, boundary);
+    }
+
+    /// Semi-Lagrangian: every cell takes the value from where its fluid came from.
+    #[allow(clippy::too_many_arguments)]
+    fn advect(config: &FluidConfig, solid: &[bool], field: &mut [f32], scratch: &mut [f32], velocity_x: &[f32], velocity_y: &[f32], dt: f32, boundary: Boundary) {
+        scratch.copy_from_slice(field);
+        let width = config.width;
+        let steps = dt / config.cell_size;
+        for y in 1..config.height - 1 {
+            for x in 1..width - 1 {
+                let i = y * width + x;
+                if solid[i] { continue; }
+                let from = Vec2::new(x as f32 - velocity_x[i] * steps, y as f32 - velocity_y[i] * steps);
+                field[i] = Self::bilinear(config, scratch, from);
+            }
+        }
+        Self::set_boundary(config, solid, field, boundary);
+    }
+
+    /// Removes divergence, so the fluid neither piles up nor leaves holes.
+    fn project(&mut self) {
+        let width = self.config.width;
+        let divergence = &mut self.divergence;
+        for y in 1..self.config.height - 1 {
+            for x in 1..width - 1 {
+                let i = y * width + x;
+                divergence[i] = -0.5 * (self.velocity_x[i + 1] - self.velocity_x[i - 1] + self.velocity_y[i + width] - self.velocity_y[i - width]);
+            }
+        }
+        Self::set_boundary(&self.config, &self.solid, divergence, Boundary::Scalar);
+        self.pressure.iter_mut().for_each(|pressure| *pressure = 0.0);
+        Self::solve(&self.config, &self.solid, &mut self.pressure, divergence, 1.0, 4.0, Boundary::Scalar);
+
+        for y in 1..self.config.height - 1 {
+            for x in 1..width - 1 {
+                let i = y * width + x;
+                if self.solid[i] { continue; }
+                self.velocity_x[i] -= 0.5 * (self.pressure[i + 1] - self.pressure[i - 1]);
+                self.velocity_y[i] -= 0.5 * (self.pressure[i + width] - self.pressure[i - width]);
+            }
+        }
+        Self::set_boundary(&self.config, &self.solid, &mut self.velocity_x, Boundary::VelocityX);
+        Self::set_boundary(&self.config, &self.solid, &mut self.velocity_y, Boundary::VelocityY);
+    }
+
+    fn bilinear(config: &FluidConfig, field: &[f32], point: Vec2) -> f32 {
+        let point = point.clamp(Vec2::ZERO, Vec2::new(config.width as f32 - 1.001, config.height as f32 - 1.001));
+        let (x, y) = (point.x as usize, point.y as usize);
+        let (tx, ty) = (point.x.fract(), point.y.fract());
+        let i = y * config.width + x;
+        let top = field[i] + (field[i + 1] - field[i]) * tx;
+        let bottom = field[i + config.width] + (field[i + config.width + 1] - field[i + config.width]) * tx;
+        top + (bottom - top) * ty
+    }
+
+    fn is_inside(&self, point: Vec2) -> bool {
+        let grid = self.to_grid(point);
+        grid.x >= 0.0 && grid.y >= 0.0 && grid.x <= self.config.width as f32 - 1.0 && grid.y <= self.config.height as f32 - 1.0
+    }
+
+    /// Flow velocity at a world position in pixels per second, zero outside the grid.
+    pub fn sample_velocity(&self, point: Vec2) -> Vec2 {
+        if !self.is_inside(point) { return Vec2::ZERO; }
+        let grid = self.to_grid(point);
+        Vec2::new(Self::bilinear(&self.config, &self.velocity_x, grid), Self::bilinear(&self.config, &self.velocity_y, grid))
+    }
+
+    pub fn sample_density(&self, point: Vec2) -> f32 {
+        if !self.is_inside(point) { return 0.0; }
+        Self::bilinear(&self.config, &self.density, self.to_grid(point))
+    }
+
+    /// Draws the density as a smoothed grey texture over the grid area, nothing while the air is clear.
+    pub fn display(&self, renderer: &mut dyn Renderer) {
+        if !self.visible {
+            return;
+        }
+        let (min, max) = (self.config.origin, self.config.origin + Vec2::new(self.config.width as f32, self.config.height as f32) * self.config.cell_size);
+        let corners = [min, Vec2::new(max.x, min.y), max, Vec2::new(min.x, max.y)];
+        let image_coords = [Vec2::ZERO, Vec2::X, Vec2::ONE, Vec2::Y];
+        renderer.draw_textured_quad(corners, [Color::WHITE; 4], image_coords, &self.texture, BlendMode::Alpha);
+    }
+}
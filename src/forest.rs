@@ -0,0 +1,160 @@
+use glam::Vec2;
+
+use crate::verlet_physics::*;
+use crate::tree_gen::*;
+use crate::species::*;
+use crate::environment::*;
+use crate::distributions::*;
+use crate::renderer::*;
+use crate::camera::*;
+
+const HORIZON: f32 = 360.0;
+
+pub struct ForestConfig {
+    pub left: f32,
+    pub right: f32,
+    /// Ground line of the front layer, further layers rise towards the horizon
+    pub ground_level: f32,
+    /// Smallest distance between trees of the front layer
+    pub min_spacing: f32,
+    pub layers: usize,
+    /// How much further away every next layer is
+    pub layer_depth: f32,
+    /// Species with their relative weights
+    pub species: Vec<(Species, f32)>,
+    /// Only the front layer is simulated, trees behind it stay still to save time
+    pub static_background: bool,
+    pub roots: bool,
+    /// Direction towards the light all trees bend to
+    pub light_direction: Vec2,
+    /// Snow piles up on branches in winter
+    pub snow: bool,
+    /// Same seed grows the same forest
+    pub seed: Option<u64>,
+}
+
+impl Default for ForestConfig {
+    fn default() -> Self {
+        ForestConfig {
+            left: 0.0,
+            right: WORLD_SIZE.x,
+            ground_level: GROUND_LEVEL,
+            min_spacing: 250.0,
+            layers: 3,
+            layer_depth: 0.6,
+            species: vec![(Species::OAK, 2.0), (Species::WILLOW, 1.0), (Species::PINE, 1.0)],
+            static_background: true,
+            roots: false,
+            light_direction: Vec2::NEG_Y,
+            snow: false,
+            seed: None,
+        }
+    }
+}
+
+/// Trees of one depth layer, standing on the same ground line.
+pub struct ForestLayer {
+    pub depth: f32,
+    pub ground_level: f32,
+    pub trees: Vec<Tree>,
+}
+
+impl ForestLayer {
+    pub fn display(&self, renderer: &mut dyn Renderer) {
+        display_ground_at(renderer, self.ground_level, self.depth);
+        for tree in &self.trees {
+            tree.display(renderer);
+        }
+    }
+}
+
+/// Layers are kept back to front, in drawing order.
+pub struct Forest {
+    pub layers: Vec<ForestLayer>,
+}
+
+/// Perspective scale of things at the given depth.
+pub fn get_depth_scale(depth: f32) -> f32 {
+    1.0 / (1.0 + depth)
+}
+
+/// Poisson-disk samples on a line, no two points closer than `min_spacing`.
+/// In one dimension it's enough to step by a random gap between r and 2r.
+fn poisson_disk_on_line(left: f32, right: f32, min_spacing: f32) -> Vec<f32> {
+    let mut samples = Vec::new();
+    let mut x = left + uniform(0.0, min_spacing);
+    while x <= right {
+        samples.push(x);
+        x += uniform(min_spacing, min_spacing * 2.0);
+    }
+    samples
+}
+
+impl Forest {
+    /// Fails when no species has a weight above 0.
+    pub fn generate(config: &ForestConfig, simulation: &mut ParticleSimulation) -> Result<Forest, String> {
+        if weighted_choice(&config.species).is_none() {
+            return Err("forest needs at least one species with a weight above 0".to_string());
+        }
+        if let Some(seed) = config.seed {
+            seed_rng(seed);
+        }
+        let mut layers = Vec::with_capacity(config.layers);
+        for layer in (0..config.layers).rev() {
+            let depth = layer as f32 * config.layer_depth;
+            let scale = get_depth_scale(depth);
+            let ground_level = HORIZON + (config.ground_level - HORIZON) * scale;
+
+            // Trees of a layer grow around each other, colliders are only in the front layer
+            let mut environment = Environment::new(config.light_direction);
+            if layer == 0 {
+                environment.colliders = simulation.colliders().to_vec();
+            }
+
+            let mut trees = Vec::new();
+            for x in poisson_disk_on_line(config.left, config.right, config.min_spacing * scale) {
+                let species = weighted_choice(&config.species).unwrap().scaled(scale);
+                let mut tree = generate_tree_in(Vec2::new(x, ground_level), species, &environment);
+                environment.add_tree(&tree);
+                tree.depth = depth;
+                tree.snow = config.snow;
+                let simulated = layer == 0 || !config.static_background;
+                if config.roots && simulated {
+                    tree.grow_roots();
+                }
+                if simulated {
+                    tree.init_simulation(simulation);
+                }
+                trees.push(tree);
+            }
+            layers.push(ForestLayer { depth, ground_level, trees });
+        }
+        Ok(Forest { layers })
+    }
+
+    pub fn trees(&self) -> impl Iterator<Item = &Tree> {
+        self.layers.iter().flat_map(|layer| layer.trees.iter())
+    }
+
+    pub fn trees_mut(&mut self) -> impl Iterator<Item = &mut Tree> {
+        self.layers.iter_mut().flat_map(|layer| layer.trees.iter_mut())
+    }
+
+    pub fn sync_from_simulation(&mut self, simulation: &ParticleSimulation) {
+        for tree in self.trees_mut() {
+            tree.sync_from_simulation(simulation);
+        }
+    }
+
+    pub fn update_season(&mut self, season: f32, dt: f32, simulation: &mut ParticleSimulation) {
+        for tree in self.trees_mut() {
+            tree.update_season(season, dt, simulation);
+        }
+    }
+
+    pub fn display(&self, renderer: &mut dyn Renderer) {
+        for layer in &self.layers {
+            layer.display(renderer);
+        }
+    }
+}
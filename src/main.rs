@@ -6,53 +6,142 @@ use speedy2d::Window;
 use speedy2d::dimen::{Vector2, Vec2};
 use speedy2d::color::Color;
 use speedy2d::shape::Rect;
-use speedy2d::window::{WindowHandler, WindowHelper, MouseButton};
+use speedy2d::window::{WindowHandler, WindowHelper, MouseButton, VirtualKeyCode, KeyScancode, MouseScrollDistance};
+use speedy2d::dimen::UVec2;
 use speedy2d::Graphics2D;
 use std::time;
 use std::thread::sleep;
 
 mod verlet_physics;
 mod tree_gen;
+mod render_helpers;
+mod tree_io;
+mod species;
+mod forest;
+mod environment;
+mod seasons;
+mod smoke;
+mod easing;
+mod curl_noise;
+mod fluid;
+mod distributions;
+mod renderer;
+mod software_renderer;
+mod export;
+mod camera;
 use tree_gen::*;
+use forest::*;
 use verlet_physics::*;
+use smoke::*;
+use fluid::*;
+use renderer::*;
+use camera::*;
 
 struct MyWindowHandler {
+    /// In screen pixels, `get_mouse_world` has it in the world
     mouse_pos: (f32, f32),
     selected_point: Option<usize>,
     last_frame: time::Instant,
     dt: f32,
     ticks: f32,
     simulation: ParticleSimulation,
-    tree: Tree,
+    forest: Forest,
+    fallen: Vec<Tree>,
+    cutting: bool,
+    season: f32,
+    smoke: SmokeGenerator,
+    /// World position the smoke pours from, None follows the mouse
+    smoke_pos: Option<glam::Vec2>,
+    smoke_texture: Texture,
+    fluid: FluidGrid,
+    pouring: bool,
+    /// Flame on the end of a rope, drag the rope around to swing it
+    torch: SmokeGenerator,
+    camera: Camera2D,
+    panning: bool,
+    /// Camera keeps the torch in view
+    following: bool,
+    /// Draws every particle and constraint over the scene, toggled with D
+    debug: bool,
 }
 
-
-fn get_rotated_quad(ix: f32, iy: f32, w: f32, h: f32, ang: f32) -> [Vec2; 4] {
-    let cos = ang.cos();
-    let sin = ang.sin();
-
-    let rot_xy = |x, y| { (ix + x * cos - y * sin, iy + x * sin + y * cos) };
-
-    let half_width = w / 2.0;
-    let half_height = h / 2.0;
-
-    let (x1, y1) = rot_xy(-half_width, -half_height);
-    let (x2, y2) = rot_xy(half_width, -half_height);
-    let (x3, y3) = rot_xy(half_width, half_height);
-    let (x4, y4) = rot_xy(-half_width, half_height);
-
-    [Vec2::new(x1, y1), Vec2::new(x2, y2), Vec2::new(x3, y3), Vec2::new(x4, y4)]
-}
+const FALL_GRAVITY: glam::Vec2 = glam::Vec2::new(0.0, 1000.0);
+const SEASON_SPEED: f32 = 1.0 / 60.0; // Years per second
+const SMOKE_BURST: usize = 30;
+const FLUID_SOURCE_RADIUS: f32 = 25.0;
+const FLUID_DENSITY_RATE: f32 = 5.0; // Per second
+const FLUID_HEAT_RATE: f32 = 10.0;
+const FLUID_WIND_DRAG: f32 = 2.0;
+const ROPE_ANCHOR: glam::Vec2 = glam::Vec2::new(1100.0, 80.0);
+const ROPE_SEGMENTS: usize = 5;
+const ROPE_SEGMENT_LENGTH: f32 = 30.0;
+const SCREEN_WIDTH: u32 = 1280;
+const SCREEN_HEIGHT: u32 = 720;
+/// Exported clips keep smoke pouring from here, in world units
+const EXPORT_SMOKE_POS: glam::Vec2 = glam::Vec2::new(640.0, 600.0);
+const PICK_RADIUS: f32 = 3.0; // Screen pixels
+const ZOOM_STEP: f32 = 1.1; // Per wheel line
+const PIXELS_PER_LINE: f32 = 40.0; // For touchpads that scroll by pixels
+const LINES_PER_PAGE: f32 = 10.0;
+/// S saves the front tree nearest the mouse here, L plants it back under the mouse
+const TREE_FILE: &str = "tree.json";
+const TREE_SVG_FILE: &str = "tree.svg";
 
 
 impl MyWindowHandler {
-    fn new(simulation: ParticleSimulation, tree: Tree) -> Self {
+    fn new(simulation: ParticleSimulation, forest: Forest, torch_particle: usize) -> Self {
         let last_frame = time::Instant::now();
         let mouse_pos = (0.0, 0.0);
         let selected_point = None;
         let dt = 0.0;
         let ticks = 0.0;
-        MyWindowHandler { mouse_pos, selected_point, last_frame, dt, ticks, simulation, tree }
+        let fallen = Vec::new();
+        let cutting = false;
+        let season = seasons::SPRING;
+        // Rises with the hot air and fades out slowly at first
+        let easing = ParticleEasing { opacity: easing::Easing::SmoothStart(2.0), ..Default::default() };
+        let motion = SmokeMotion::Physical(SmokePhysics::default());
+        let turbulence = Some(curl_noise::CurlNoise::default());
+        let config = EmitterConfig {
+            shape: EmitterShape::Circle(20.0),
+            motion,
+            turbulence,
+            easing,
+            // Drifts in and out between the forest layers
+            depth: Range::new(-0.3, 1.2),
+            emitter_fade: 40.0,
+            collider_fade: 30.0,
+            ..Default::default()
+        };
+        let mut smoke = SmokeGenerator::new(0.0, 0.0, config);
+        smoke.active = false;
+        let smoke_texture = get_smoke_texture();
+        let mut fluid = FluidGrid::new(FluidConfig::default());
+        fluid.set_obstacles(simulation.colliders());
+        let pouring = false;
+        let flame = EmitterConfig {
+            emission: Emission::Continuous(40.0),
+            lifetime: Range::new(0.6, 1.0),
+            travel: glam::Vec2::new(0.0, -80.0),
+            spread: 20.0,
+            start_size: Range::new(20.0, 30.0),
+            end_size: Range::new(40.0, 80.0),
+            start_opacity: Range::exactly(0.6),
+            gradient: ColorGradient::fire(),
+            blend: BlendMode::Additive,
+            ..Default::default()
+        };
+        let mut torch = SmokeGenerator::new(0.0, 0.0, flame);
+        torch.attach(torch_particle, glam::Vec2::ZERO);
+        let camera = Camera2D::new(glam::Vec2::new(SCREEN_WIDTH as f32, SCREEN_HEIGHT as f32));
+        let panning = false;
+        let following = false;
+        let smoke_pos = None;
+        let debug = false;
+        MyWindowHandler {
+            mouse_pos, selected_point, last_frame, dt, ticks, simulation, forest, fallen, cutting, season,
+            smoke, smoke_pos, smoke_texture, fluid, pouring, torch, camera, panning, following, debug,
+        }
     }
 
     fn calc_fps(&mut self) {
@@ -65,10 +154,104 @@ impl MyWindowHandler {
         println!("fps: {fps}");
     }
 
-    fn _draw(&mut self, graphics: &mut Graphics2D) {
-        self.simulation.display(graphics);
-        //self.tree.display(graphics);
+    fn get_mouse_world(&self) -> glam::Vec2 {
+        self.camera.screen_to_world(self.mouse_pos.into())
+    }
+
+    fn _draw(&mut self, renderer: &mut dyn Renderer) {
+        if self.following {
+            self.camera.follow(glam::Vec2::new(self.torch.x, self.torch.y));
+        }
+        self.camera.update(self.dt);
+        let mouse = self.get_mouse_world();
+        let renderer = &mut CameraRenderer::new(renderer, &self.camera);
+
+        // Hot smoke pours out of the cursor while F is held, its flow blows the trees and smoke around
+        if self.pouring {
+            let dt = self.dt;
+            self.fluid.add_source(mouse, FLUID_SOURCE_RADIUS, FLUID_DENSITY_RATE * dt, FLUID_HEAT_RATE * dt, glam::Vec2::ZERO);
+        }
+        self.fluid.step(self.dt);
+        let fluid = &self.fluid;
+        self.simulation.apply_wind(|point| fluid.sample_velocity(point), FLUID_WIND_DRAG);
+        self.smoke.follow_flow(|point| fluid.sample_velocity(point), self.dt);
+
+        // Smoke puffs out of the cursor while space is held
+        (self.smoke.x, self.smoke.y) = self.smoke_pos.unwrap_or(mouse).into();
+        self.smoke.update(self.dt, &self.simulation);
+        self.torch.update(self.dt, &self.simulation);
+        let texture = &self.smoke_texture;
+
+        // Smoke goes in between the forest layers, in front of trees at its own depth
+        let mut furthest = f32::INFINITY;
+        for layer in &self.forest.layers {
+            self.smoke.display_between(renderer, texture, layer.depth, furthest);
+            // Layers further back follow less of the pan
+            renderer.depth_scale = get_depth_scale(layer.depth);
+            layer.display(renderer);
+            renderer.depth_scale = 1.0;
+            furthest = layer.depth;
+        }
+        if self.debug {
+            self.simulation.display(renderer);
+        } else {
+            self.simulation.display_colliders(renderer);
+        }
+        for piece in &self.fallen {
+            piece.display(renderer);
+            piece.accelerate(&mut self.simulation, FALL_GRAVITY);
+        }
+        self.fluid.display(renderer);
+        self.smoke.display_between(renderer, texture, f32::NEG_INFINITY, furthest);
+        self.torch.display(renderer, texture);
+
+        self.season = seasons::wrap_season(self.season + self.dt * SEASON_SPEED);
+        self.forest.update_season(self.season, self.dt, &mut self.simulation);
+        for piece in &mut self.fallen {
+            piece.update_season(self.season, self.dt, &mut self.simulation);
+        }
+
+        if let Some(attachment) = self.torch.attachment {
+            self.simulation.particles[attachment.particle].accelerate(FALL_GRAVITY);
+        }
+
         self.simulation.physics_step();
+        self.forest.sync_from_simulation(&self.simulation);
+        for piece in &mut self.fallen {
+            piece.keep_above_ground(&mut self.simulation);
+            piece.sync_from_simulation(&self.simulation);
+        }
+    }
+
+    /// Writes the front tree nearest to the mouse as a skeleton and as a picture.
+    fn save_tree(&self) -> Result<(), String> {
+        let mouse = self.get_mouse_world();
+        let layer = self.forest.layers.last().ok_or("there is no forest")?;
+        let tree = layer.trees.iter()
+            .min_by(|a, b| a.root_pos().distance(mouse).total_cmp(&b.root_pos().distance(mouse)))
+            .ok_or("there are no trees in front")?;
+        tree.save_skeleton(TREE_FILE)?;
+        tree.save_svg(TREE_SVG_FILE)
+    }
+
+    /// Plants the saved tree into the front layer, standing under the mouse.
+    fn load_tree(&mut self) -> Result<(), String> {
+        let mouse = self.get_mouse_world();
+        let layer = self.forest.layers.last_mut().ok_or("there is no forest")?;
+        let mut tree = Tree::load_skeleton_at(TREE_FILE, glam::Vec2::new(mouse.x, layer.ground_level))?;
+        tree.depth = layer.depth;
+        tree.init_simulation(&mut self.simulation);
+        layer.trees.push(tree);
+        Ok(())
+    }
+
+    /// Cuts everything the mouse crossed since the last move.
+    fn cut(&mut self, from: glam::Vec2, to: glam::Vec2) {
+        let mut pieces = Vec::new();
+        for tree in self.forest.trees_mut().chain(self.fallen.iter_mut()) {
+            pieces.extend(tree.cut(from, to, &mut self.simulation));
+        }
+        self.fallen.extend(pieces);
     }
 }
 
@@ -77,23 +260,46 @@ impl WindowHandler for MyWindowHandler
 {
     fn on_draw(&mut self, helper: &mut WindowHelper, graphics: &mut Graphics2D)
     {
-        graphics.clear_screen(Color::from_rgba(0.0, 0.0, 0.0, 1.0));
+        let renderer = &mut WindowRenderer { graphics, size: self.camera.viewport };
+        renderer.clear(Color::from_rgba(0.0, 0.0, 0.0, 1.0));
         self.calc_fps();
         if let Some(point) = self.selected_point {
-            self.simulation.particles[point].pos = self.mouse_pos.into();
+            self.simulation.particles[point].pos = self.get_mouse_world();
         }
-        self._draw(graphics);
+        self._draw(renderer);
         helper.request_redraw();
     }
 
+    fn on_resize(&mut self, _helper: &mut WindowHelper, size_pixels: UVec2) {
+        self.camera.viewport = glam::Vec2::new(size_pixels.x as f32, size_pixels.y as f32);
+    }
+
+    fn on_mouse_wheel_scroll(&mut self, _helper: &mut WindowHelper, distance: MouseScrollDistance) {
+        let lines = match distance {
+            MouseScrollDistance::Lines { y, .. } => y as f32,
+            MouseScrollDistance::Pixels { y, .. } => y as f32 / PIXELS_PER_LINE,
+            MouseScrollDistance::Pages { y, .. } => y as f32 * LINES_PER_PAGE,
+        };
+        self.camera.zoom_at(self.mouse_pos.into(), ZOOM_STEP.powf(lines));
+    }
+
     fn on_mouse_move(&mut self, _helper: &mut WindowHelper, mouse_pos: Vector2<f32>){
+        let last_pos = self.mouse_pos;
         self.mouse_pos = (mouse_pos.x, mouse_pos.y);
+        if self.panning {
+            self.camera.pan(glam::Vec2::from(self.mouse_pos) - glam::Vec2::from(last_pos));
+            self.following = false;
+        }
+        if self.cutting {
+            self.cut(self.camera.screen_to_world(last_pos.into()), self.get_mouse_world());
+        }
     }
 
     fn on_mouse_button_down(&mut self, _helper: &mut WindowHelper, mouse_button: MouseButton) {
         match mouse_button {
-            MouseButton::Left => { self.selected_point = self.simulation.select_point(self.mouse_pos.into())},
-            MouseButton::Right => { },
+            MouseButton::Left => { self.selected_point = self.simulation.select_point(self.get_mouse_world(), PICK_RADIUS / self.camera.zoom)},
+            MouseButton::Right => { self.cutting = true },
+            MouseButton::Middle => { self.panning = true },
             _ => {},
         }
     }
@@ -101,16 +307,45 @@ impl WindowHandler for MyWindowHandler
     fn on_mouse_button_up(&mut self, _helper: &mut WindowHelper, mouse_button: MouseButton) {
         match mouse_button {
             MouseButton::Left => { self.selected_point = None },
-            MouseButton::Right => { },
+            MouseButton::Right => { self.cutting = false },
+            MouseButton::Middle => { self.panning = false },
             _ => {},
         }
     }
 
+    fn on_key_down(&mut self, _helper: &mut WindowHelper, virtual_key_code: Option<VirtualKeyCode>, _scancode: KeyScancode) {
+        match virtual_key_code {
+            Some(VirtualKeyCode::Space) => { self.smoke.active = true },
+            Some(VirtualKeyCode::B) => { self.smoke.burst(SMOKE_BURST) },
+            Some(VirtualKeyCode::F) => { self.pouring = true },
+            Some(VirtualKeyCode::S) => match self.save_tree() {
+                Ok(()) => println!("saved {TREE_FILE} and {TREE_SVG_FILE}"),
+                Err(e) => eprintln!("saving tree failed: {e}"),
+            },
+            Some(VirtualKeyCode::L) => match self.load_tree() {
+                Ok(()) => println!("planted {TREE_FILE}"),
+                Err(e) => eprintln!("loading tree failed: {e}"),
+            },
+            Some(VirtualKeyCode::D) => { self.debug = !self.debug },
+            Some(VirtualKeyCode::C) => {
+                self.following = !self.following;
+                if !self.following { self.camera.target = None; }
+            },
+            _ => {},
+        }
+    }
 
+    fn on_key_up(&mut self, _helper: &mut WindowHelper, virtual_key_code: Option<VirtualKeyCode>, _scancode: KeyScancode) {
+        match virtual_key_code {
+            Some(VirtualKeyCode::Space) => { self.smoke.active = false },
+            Some(VirtualKeyCode::F) => { self.pouring = false },
+            _ => {},
+        }
+    }
 }
 
-fn main() {
-    let window = Window::new_centered("Hello testing", (1280, 720)).unwrap();
+/// Particle toys, rope with the torch and the forest, shared by the window and exports.
+fn build_scene(seed: Option<u64>) -> Result<MyWindowHandler, String> {
     let mut simulation = ParticleSimulation::new();
 
     let a = simulation.new_particle(glam::Vec2::new(100.0, 100.0), 3.0, 1.0, true);
@@ -121,8 +356,57 @@ fn main() {
     simulation.new_angle_constrain_in_place(a, b, c);
 
 
-    let tree = generate_tree();
-    //tree.init_simulation(&mut simulation);
-    let window_handler = MyWindowHandler::new(simulation, tree);
+    // Rope hanging sideways from a fixed anchor, so it swings right away
+    let mut torch = simulation.new_particle(ROPE_ANCHOR, 3.0, 1.0, true);
+    for i in 1..=ROPE_SEGMENTS {
+        let next = simulation.new_particle(ROPE_ANCHOR + glam::Vec2::X * ROPE_SEGMENT_LENGTH * i as f32, 3.0, 1.0, false);
+        simulation.new_distance_constrain_in_place(torch, next);
+        torch = next;
+    }
+
+    let config = ForestConfig { roots: true, snow: true, seed, ..Default::default() };
+    let forest = Forest::generate(&config, &mut simulation)?;
+    Ok(MyWindowHandler::new(simulation, forest, torch))
+}
+
+/// Run with `export <output> [options]` to render a clip without opening a window, see export.rs.
+fn export_scene(args: &[String]) -> Result<(), String> {
+    let config = export::ExportConfig::from_args(args)?;
+    let mut handler = build_scene(config.seed)?;
+    handler.simulation.timestep = config.get_timestep();
+    // Clips are for showing off the scene, never the physics debug view
+    handler.debug = false;
+    handler.smoke.active = true;
+    handler.smoke_pos = Some(EXPORT_SMOKE_POS);
+    // Whole scene fits in the picture, other aspect ratios show more of the world around it
+    let fit = (config.width as f32 / WORLD_SIZE.x).min(config.height as f32 / WORLD_SIZE.y);
+    handler.camera.viewport = glam::Vec2::new(config.width as f32, config.height as f32) / fit;
+    let scene_size = handler.camera.viewport;
+    export::export(&config, scene_size, |renderer, dt| {
+        handler.dt = dt;
+        handler.ticks += dt;
+        renderer.clear(Color::from_rgba(0.0, 0.0, 0.0, 1.0));
+        handler._draw(renderer);
+    })
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("export") {
+        if let Err(e) = export_scene(&args[2..]) {
+            eprintln!("export failed: {e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let window_handler = match build_scene(None) {
+        Ok(handler) => handler,
+        Err(e) => {
+            eprintln!("couldn't build the scene: {e}");
+            std::process::exit(1);
+        },
+    };
+    let window = Window::new_centered("Hello testing", (SCREEN_WIDTH, SCREEN_HEIGHT)).unwrap();
     window.run_loop::<MyWindowHandler>(window_handler);
 }
@@ -1,3 +1,5 @@
+use speedy2d::dimen::Vec2;
+
 pub fn hsv_to_rgb(h: f32, s: f32, v: f32) -> (f32, f32, f32) {
     if s == 0.0 { return (v, v, v) };
     let i = (h*6.0).trunc();
@@ -13,3 +15,43 @@ pub fn hsv_to_rgb(h: f32, s: f32, v: f32) -> (f32, f32, f32) {
         _ => { unreachable!() },
     }
 }
+
+/// Inverse of `hsv_to_rgb`, all channels in 0..1.
+pub fn rgb_to_hsv(r: f32, g: f32, b: f32) -> (f32, f32, f32) {
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+    if max == 0.0 { return (0.0, 0.0, 0.0) };
+    let s = delta / max;
+    if delta == 0.0 { return (0.0, s, max) };
+    let h = if max == r {
+        (g - b) / delta
+    } else if max == g {
+        (b - r) / delta + 2.0
+    } else {
+        (r - g) / delta + 4.0
+    };
+    ((h / 6.0).rem_euclid(1.0), s, max)
+}
+
+pub fn to_speedy(v: glam::Vec2) -> Vec2 {
+    Vec2::new(v.x, v.y)
+}
+
+/// Corners of a `w` by `h` rectangle centered at (`ix`, `iy`) and rotated by `ang`.
+pub fn get_rotated_quad(ix: f32, iy: f32, w: f32, h: f32, ang: f32) -> [glam::Vec2; 4] {
+    let cos = ang.cos();
+    let sin = ang.sin();
+
+    let rot_xy = |x, y| { (ix + x * cos - y * sin, iy + x * sin + y * cos) };
+
+    let half_width = w / 2.0;
+    let half_height = h / 2.0;
+
+    let (x1, y1) = rot_xy(-half_width, -half_height);
+    let (x2, y2) = rot_xy(half_width, -half_height);
+    let (x3, y3) = rot_xy(half_width, half_height);
+    let (x4, y4) = rot_xy(-half_width, half_height);
+
+    [glam::Vec2::new(x1, y1), glam::Vec2::new(x2, y2), glam::Vec2::new(x3, y3), glam::Vec2::new(x4, y4)]
+}
@@ -0,0 +1,168 @@
+use glam::Vec2;
+use speedy2d::Graphics2D;
+use speedy2d::color::Color;
+use speedy2d::dimen::UVec2;
+use speedy2d::image::{ImageDataType, ImageHandle, ImageSmoothingMode};
+use std::cell::RefCell;
+use std::path::Path;
+
+use crate::render_helpers::*;
+
+/*
+ * Everything in the scene draws through `Renderer`, so the same scene can go to the window
+ * through speedy2d or into an image through the software renderer.
+ * Quad corners go clockwise on screen, like speedy2d wants them.
+ */
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum BlendMode {
+    /// Covers what's behind, smoke and dust
+    Alpha,
+    /// Lights up what's behind, fire and sparks. Only the software renderer does this,
+    /// speedy2d can only blend by alpha so the window draws it exactly like `Alpha`.
+    Additive,
+}
+
+/// RGBA image kept in memory, uploaded to the GPU the first time speedy2d draws it.
+pub struct Texture {
+    pub width: u32,
+    pub height: u32,
+    /// 8 bits per channel, row by row from the top
+    pixels: Vec<u8>,
+    handle: RefCell<Option<ImageHandle>>,
+}
+
+impl Texture {
+    pub fn from_rgba(width: u32, height: u32, pixels: Vec<u8>) -> Self {
+        assert_eq!(pixels.len(), (width * height * 4) as usize, "texture needs 4 bytes per pixel");
+        Texture { width, height, pixels, handle: RefCell::new(None) }
+    }
+
+    /// Decodes an image file already read into memory, any format the image crate knows.
+    pub fn from_file_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let image = image::load_from_memory(bytes).map_err(|e| e.to_string())?.to_rgba8();
+        Ok(Texture::from_rgba(image.width(), image.height(), image.into_raw()))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+        Texture::from_file_bytes(&bytes)
+    }
+
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    /// Changed pixels are uploaded again the next time speedy2d draws the texture.
+    pub fn pixels_mut(&mut self) -> &mut [u8] {
+        self.handle.get_mut().take();
+        &mut self.pixels
+    }
+
+    fn get_pixel(&self, x: u32, y: u32) -> [f32; 4] {
+        let i = ((y * self.width + x) * 4) as usize;
+        let p = &self.pixels[i..i + 4];
+        [p[0] as f32 / 255.0, p[1] as f32 / 255.0, p[2] as f32 / 255.0, p[3] as f32 / 255.0]
+    }
+
+    /// Bilinear sample at normalized image coordinates, clamped to the edges.
+    pub fn sample(&self, coords: Vec2) -> [f32; 4] {
+        let x = (coords.x * self.width as f32 - 0.5).clamp(0.0, self.width as f32 - 1.0);
+        let y = (coords.y * self.height as f32 - 0.5).clamp(0.0, self.height as f32 - 1.0);
+        let (x0, y0) = (x as u32, y as u32);
+        let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+        let (tx, ty) = (x.fract(), y.fract());
+        let (a, b, c, d) = (self.get_pixel(x0, y0), self.get_pixel(x1, y0), self.get_pixel(x0, y1), self.get_pixel(x1, y1));
+        std::array::from_fn(|k| {
+            let top = a[k] + (b[k] - a[k]) * tx;
+            let bottom = c[k] + (d[k] - c[k]) * tx;
+            top + (bottom - top) * ty
+        })
+    }
+}
+
+pub trait Renderer {
+    fn clear(&mut self, color: Color);
+
+    fn draw_circle(&mut self, center: Vec2, radius: f32, color: Color);
+
+    fn draw_line(&mut self, start: Vec2, end: Vec2, thickness: f32, color: Color);
+
+    /// Colors are blended across the quad between its corners.
+    fn draw_quad(&mut self, corners: [Vec2; 4], colors: [Color; 4]);
+
+    /// Part of the texture between `image_coords` (normalized 0..1) stretched over the quad,
+    /// multiplied by the corner colors.
+    fn draw_textured_quad(&mut self, corners: [Vec2; 4], colors: [Color; 4], image_coords: [Vec2; 4], texture: &Texture, blend: BlendMode);
+
+    fn draw_rectangle(&mut self, min: Vec2, max: Vec2, color: Color) {
+        let corners = [min, Vec2::new(max.x, min.y), max, Vec2::new(min.x, max.y)];
+        self.draw_quad(corners, [color; 4]);
+    }
+
+    /// Top left and bottom right corners of what ends up on screen, for things that reach the edges.
+    fn get_visible_area(&self) -> (Vec2, Vec2);
+}
+
+/// Draws into the window through speedy2d, which doesn't know how big the window is.
+pub struct WindowRenderer<'a> {
+    pub graphics: &'a mut Graphics2D,
+    /// Window size in pixels
+    pub size: Vec2,
+}
+
+impl Renderer for WindowRenderer<'_> {
+    fn clear(&mut self, color: Color) {
+        self.graphics.clear_screen(color);
+    }
+
+    fn draw_circle(&mut self, center: Vec2, radius: f32, color: Color) {
+        self.graphics.draw_circle(to_speedy(center), radius, color);
+    }
+
+    fn draw_line(&mut self, start: Vec2, end: Vec2, thickness: f32, color: Color) {
+        self.graphics.draw_line(to_speedy(start), to_speedy(end), thickness, color);
+    }
+
+    fn draw_quad(&mut self, corners: [Vec2; 4], colors: [Color; 4]) {
+        self.graphics.draw_quad_four_color(corners.map(to_speedy), colors);
+    }
+
+    /// Blend mode is ignored, speedy2d can only blend by alpha.
+    fn draw_textured_quad(&mut self, corners: [Vec2; 4], colors: [Color; 4], image_coords: [Vec2; 4], texture: &Texture, _blend: BlendMode) {
+        let mut handle = texture.handle.borrow_mut();
+        let handle = handle.get_or_insert_with(|| {
+            let size = UVec2::new(texture.width, texture.height);
+            self.graphics.create_image_from_raw_pixels(ImageDataType::RGBA, ImageSmoothingMode::Linear, size, &texture.pixels).unwrap()
+        });
+        self.graphics.draw_quad_image_tinted_four_color(corners.map(to_speedy), colors, image_coords.map(to_speedy), handle);
+    }
+
+    fn get_visible_area(&self) -> (Vec2, Vec2) {
+        (Vec2::ZERO, self.size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_clamps_to_edges() {
+        // Red on the left, blue on the right
+        let texture = Texture::from_rgba(2, 1, vec![255, 0, 0, 255, 0, 0, 255, 255]);
+        assert_eq!(texture.sample(Vec2::new(0.0, 0.5)), [1.0, 0.0, 0.0, 1.0]);
+        assert_eq!(texture.sample(Vec2::new(0.25, 0.0)), [1.0, 0.0, 0.0, 1.0]);
+        assert_eq!(texture.sample(Vec2::new(1.0, 1.0)), [0.0, 0.0, 1.0, 1.0]);
+        assert_eq!(texture.sample(Vec2::new(-3.0, 2.0)), [1.0, 0.0, 0.0, 1.0]);
+        assert_eq!(texture.sample(Vec2::new(0.5, 0.5)), [0.5, 0.0, 0.5, 1.0]);
+    }
+
+    #[test]
+    fn single_pixel_samples_the_same_everywhere() {
+        let texture = Texture::from_rgba(1, 1, vec![0, 255, 0, 255]);
+        for coords in [Vec2::ZERO, Vec2::ONE, Vec2::splat(0.5), Vec2::new(7.0, -7.0)] {
+            assert_eq!(texture.sample(coords), [0.0, 1.0, 0.0, 1.0]);
+        }
+    }
+}
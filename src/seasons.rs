@@ -0,0 +1,72 @@
+use speedy2d::color::Color;
+
+use crate::render_helpers::*;
+use crate::easing::*;
+
+/*
+ * Season goes from 0 to 1 over a year:
+ * 0.0 spring, 0.25 summer, 0.5 autumn, 0.75 winter, 1.0 spring again
+ */
+
+pub const SPRING: f32 = 0.0;
+pub const SUMMER: f32 = 0.25;
+pub const AUTUMN: f32 = 0.5;
+pub const WINTER: f32 = 0.75;
+
+/// Season, hue, saturation, value
+const LEAF_COLORS: [(f32, f32, f32, f32); 7] = [
+    (0.00, 0.24, 0.55, 0.80), // Fresh spring leaves
+    (0.20, 0.30, 0.75, 0.55), // Summer green
+    (0.48, 0.30, 0.75, 0.50),
+    (0.56, 0.13, 0.85, 0.75), // Yellow
+    (0.63, 0.06, 0.90, 0.70), // Orange
+    (0.70, 0.01, 0.80, 0.45), // Red going brown
+    (1.00, 0.01, 0.80, 0.45),
+];
+
+/// Season, fraction of leaves on branches
+const LEAF_DENSITY: [(f32, f32); 5] = [
+    (0.00, 0.0),
+    (0.15, 1.0),
+    (0.55, 1.0),
+    (0.72, 0.0),
+    (1.00, 0.0),
+];
+
+/// Season, how much snow lies on branches
+const SNOW_COVER: [(f32, f32); 5] = [
+    (0.00, 0.0),
+    (0.74, 0.0),
+    (0.85, 1.0),
+    (0.95, 1.0),
+    (1.00, 0.0),
+];
+
+pub fn wrap_season(season: f32) -> f32 {
+    season.rem_euclid(1.0)
+}
+
+/// Fraction of leaves that are on the branches.
+pub fn get_leaf_density(season: f32) -> f32 {
+    keyframes(&LEAF_DENSITY, wrap_season(season))
+}
+
+/// Are leaves falling right now, as opposed to growing back.
+pub fn is_leaf_fall(season: f32) -> bool {
+    (AUTUMN..WINTER).contains(&wrap_season(season))
+}
+
+pub fn get_snow_cover(season: f32) -> f32 {
+    keyframes(&SNOW_COVER, wrap_season(season))
+}
+
+/// Leaf color for the season. Variation shifts the season a bit, so leaves turn one by one.
+pub fn get_leaf_color(season: f32, variation: f32) -> Color {
+    let season = (wrap_season(season) + variation).clamp(0.0, 1.0);
+    let channel = |pick: fn(&(f32, f32, f32, f32)) -> f32| {
+        let keys = LEAF_COLORS.map(|key| (key.0, pick(&key)));
+        keyframes(&keys, season)
+    };
+    let (r, g, b) = hsv_to_rgb(channel(|key| key.1), channel(|key| key.2), channel(|key| key.3));
+    Color::from_rgb(r, g, b)
+}
@@ -1,142 +1,753 @@
-use rand::{Rng, thread_rng, random};
+use speedy2d::color::Color;
 use std::f32::consts::PI;
+use std::path::Path;
+
+use crate::render_helpers::*;
+use crate::easing::*;
+use crate::verlet_physics::*;
+use crate::curl_noise::*;
+use crate::distributions::*;
+use crate::renderer::*;
+
+const SMOKE_TEXTURE: &[u8] = include_bytes!("resources/smoke_particle.png");
 
 struct ParticleState {
-    x: f32,
-    y: f32,
+    pos: glam::Vec2,
     angle: f32, size: f32, opacity: f32,
 }
 
-pub struct SmokeParticle {
-    time_alive: f32,
+/// Everything a particle is born with, before it goes into the pool.
+struct NewParticle {
     time_to_live: f32,
     start_state: ParticleState,
     end_state: ParticleState,
+    velocity: glam::Vec2,
+    swirl: glam::Vec2,
+    tint: Color,
+    depth: f32,
+    start_frame: f32,
+}
+
+/// Live particles stored field by field, so a pass over one property streams through memory.
+/// Room for `capacity` particles is made up front and dead ones are swap-removed,
+/// so the live ones stay packed at the front and nothing is allocated while running.
+pub struct SmokePool {
+    capacity: usize,
+    time_alive: Vec<f32>,
+    time_to_live: Vec<f32>,
+    start_pos: Vec<glam::Vec2>,
+    end_pos: Vec<glam::Vec2>,
+    start_angle: Vec<f32>,
+    end_angle: Vec<f32>,
+    start_size: Vec<f32>,
+    end_size: Vec<f32>,
+    start_opacity: Vec<f32>,
+    end_opacity: Vec<f32>,
+    pos: Vec<glam::Vec2>,
+    /// Pixels per second, only used when the smoke is physical
+    velocity: Vec<glam::Vec2>,
+    /// How far turbulence carried a tweened particle off its path
+    drift: Vec<glam::Vec2>,
+    /// Turbulence velocity last sampled for the particle, pixels per second
+    swirl: Vec<glam::Vec2>,
+    /// Multiplies the color gradient, so particles differ a bit
+    tint: Vec<Color>,
+    /// Same scale as tree depth, 0 is the front layer
+    depth: Vec<f32>,
+    /// Opacity left after soft edges near the emitter and colliders
+    softness: Vec<f32>,
+    start_frame: Vec<f32>,
+}
+
+/// Where on the emitter new particles appear, relative to its position.
+#[derive(Clone, Copy)]
+pub enum EmitterShape {
+    Point,
+    /// From the emitter to emitter + offset
+    Line(glam::Vec2),
+    /// Anywhere inside the radius
+    Circle(f32),
+    /// Width and height, centered on the emitter
+    Rect(glam::Vec2),
+}
+
+#[derive(Clone, Copy)]
+pub enum Emission {
+    /// Particles per second
+    Continuous(f32),
+    /// `count` particles at once every `interval` seconds
+    Burst { count: usize, interval: f32 },
+}
+
+/// Every particle gets a value picked uniformly between min and max.
+#[derive(Clone, Copy)]
+pub struct Range {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Range {
+    pub const fn new(min: f32, max: f32) -> Self {
+        Range { min, max }
+    }
+
+    pub const fn exactly(value: f32) -> Self {
+        Range { min: value, max: value }
+    }
+
+    pub fn sample(&self) -> f32 {
+        uniform(self.min, self.max)
+    }
+}
+
+/// Forces moving a physical smoke particle, accelerations are in pixels per second squared.
+#[derive(Clone, Copy)]
+pub struct SmokePhysics {
+    /// Pixels per second at spawn
+    pub speed: Range,
+    /// Radians, where particles are shot at spawn
+    pub direction: Range,
+    /// Upward push of hot smoke
+    pub buoyancy: f32,
+    /// Fraction of velocity lost per second
+    pub drag: f32,
+    /// Strength of random pushes
+    pub turbulence: f32,
+}
+
+impl Default for SmokePhysics {
+    fn default() -> Self {
+        SmokePhysics {
+            speed: Range::new(50.0, 150.0),
+            direction: Range::new(-PI * 0.75, -PI * 0.25),
+            buoyancy: 150.0,
+            drag: 1.5,
+            turbulence: 400.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum SmokeMotion {
+    /// Particles glide from where they spawn to a random end point
+    Tween,
+    /// Particles are pushed around by forces and colliders of the simulation
+    Physical(SmokePhysics),
+}
+
+#[derive(Clone)]
+pub struct EmitterConfig {
+    pub emission: Emission,
+    pub shape: EmitterShape,
+    /// Most particles alive at once, new ones are dropped while the pool is full
+    pub capacity: usize,
+    /// Seconds
+    pub lifetime: Range,
+    pub motion: SmokeMotion,
+    /// Swirls carrying the particles along, whatever their motion
+    pub turbulence: Option<CurlNoise>,
+    /// Where tweened particles drift to over their life, relative to where they were spawned
+    pub travel: glam::Vec2,
+    /// How far the end point can be off `travel`, on each axis
+    pub spread: f32,
+    pub start_size: Range,
+    pub end_size: Range,
+    pub rotation: Range,
+    /// Radians turned over the whole life
+    pub spin: Range,
+    pub start_opacity: Range,
+    pub end_opacity: Range,
+    /// Color over the life of a particle, alpha multiplies the opacity
+    pub gradient: ColorGradient,
+    /// Each particle is tinted somewhere between the two
+    pub tint: (Color, Color),
+    pub blend: BlendMode,
+    pub easing: ParticleEasing,
+    /// Same scale as tree depth, 0 is the front layer of the forest and below 0 is in front of it
+    pub depth: Range,
+    /// Pixels a particle travels from its spawn point before it's fully visible, 0 turns it off
+    pub emitter_fade: f32,
+    /// Particles closer than this many pixels to a collider fade out, 0 turns it off
+    pub collider_fade: f32,
+    pub sheet: SpriteSheet,
+    pub frame_mode: FrameMode,
+    /// Frame particles start on, a random one when None
+    pub start_frame: Option<u32>,
+    /// Cross-fades between frames instead of snapping, smoother at low frame rates
+    pub frame_blending: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ColorSpace {
+    Rgb,
+    /// Goes around the color wheel, yellow to red passes orange instead of going muddy
+    Hsv,
+}
+
+/// Colors at points of a particle's life, from 0 at spawn to 1 at death.
+#[derive(Clone)]
+pub struct ColorGradient {
+    /// (t, color) keys sorted by t
+    pub keys: Vec<(f32, Color)>,
+    pub space: ColorSpace,
+}
+
+impl ColorGradient {
+    pub fn new(keys: Vec<(f32, Color)>) -> Self {
+        ColorGradient { keys, space: ColorSpace::Hsv }
+    }
+
+    /// Fire turning into smoke: yellow, orange, grey, gone.
+    pub fn fire() -> Self {
+        ColorGradient::new(vec![
+            (0.0, Color::from_rgba(1.0, 0.95, 0.5, 1.0)),
+            (0.15, Color::from_rgba(1.0, 0.5, 0.1, 1.0)),
+            (0.4, Color::from_rgba(0.4, 0.4, 0.4, 0.8)),
+            (1.0, Color::from_rgba(0.5, 0.5, 0.5, 0.0)),
+        ])
+    }
+
+    pub fn sample(&self, t: f32) -> Color {
+        let Some((i, t)) = find_key(&self.keys, t) else { return Color::WHITE };
+        let (a, b) = (self.keys[i].1, self.keys[(i + 1).min(self.keys.len() - 1)].1);
+        match self.space {
+            ColorSpace::Rgb => lerp_color(a, b, t),
+            ColorSpace::Hsv => lerp_color_hsv(a, b, t),
+        }
+    }
+}
+
+/// Texture split into a grid of equally sized frames, read left to right, top to bottom.
+#[derive(Clone, Copy)]
+pub struct SpriteSheet {
+    pub columns: u32,
+    pub rows: u32,
+    /// Last row can be partly empty
+    pub frames: u32,
+}
+
+impl SpriteSheet {
+    /// Whole texture is one frame.
+    pub const SINGLE: SpriteSheet = SpriteSheet { columns: 1, rows: 1, frames: 1 };
+
+    pub fn grid(columns: u32, rows: u32) -> Self {
+        SpriteSheet { columns, rows, frames: columns * rows }
+    }
+
+    /// Corners of the frame in normalized image coordinates, in the same order as `get_rotated_quad`.
+    pub fn get_frame_coords(&self, frame: u32) -> [glam::Vec2; 4] {
+        let (width, height) = (1.0 / self.columns as f32, 1.0 / self.rows as f32);
+        let left = (frame % self.columns) as f32 * width;
+        let top = (frame / self.columns) as f32 * height;
+        [glam::Vec2::new(left, top), glam::Vec2::new(left + width, top), glam::Vec2::new(left + width, top + height), glam::Vec2::new(left, top + height)]
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum FrameMode {
+    /// Particle keeps its start frame
+    Still,
+    /// Plays at a fixed rate from the start frame
+    Flipbook { frames_per_second: f32, looping: bool },
+    /// Plays every frame once over the life of the particle, whatever it is
+    OverLifetime,
+}
+
+/// How each property of a particle goes from its start to its end value.
+#[derive(Clone, Default)]
+pub struct ParticleEasing {
+    pub position: Easing,
+    pub size: Easing,
+    pub opacity: Easing,
+    pub angle: Easing,
+    pub color: Easing,
+}
+
+impl Default for EmitterConfig {
+    fn default() -> Self {
+        EmitterConfig {
+            emission: Emission::Continuous(60.0),
+            shape: EmitterShape::Point,
+            capacity: 4096,
+            lifetime: Range::exactly(3.0),
+            motion: SmokeMotion::Tween,
+            turbulence: None,
+            travel: glam::Vec2::new(0.0, -300.0),
+            spread: 200.0,
+            start_size: Range::new(50.0, 100.0),
+            end_size: Range::new(200.0, 600.0),
+            rotation: Range::new(-PI, PI),
+            spin: Range::new(-PI, PI),
+            start_opacity: Range::exactly(0.1),
+            end_opacity: Range::exactly(0.0),
+            gradient: ColorGradient::new(vec![(0.0, Color::WHITE)]),
+            tint: (Color::WHITE, Color::WHITE),
+            blend: BlendMode::Alpha,
+            easing: ParticleEasing::default(),
+            depth: Range::exactly(0.0),
+            emitter_fade: 0.0,
+            collider_fade: 0.0,
+            sheet: SpriteSheet::SINGLE,
+            frame_mode: FrameMode::Still,
+            start_frame: Some(0),
+            frame_blending: true,
+        }
+    }
+}
+
+impl EmitterShape {
+    fn sample(&self) -> glam::Vec2 {
+        match *self {
+            EmitterShape::Point => glam::Vec2::ZERO,
+            EmitterShape::Line(offset) => offset * unit(),
+            EmitterShape::Circle(radius) => on_disk(radius),
+            EmitterShape::Rect(size) => size * (glam::Vec2::new(unit(), unit()) - 0.5),
+        }
+    }
+}
+
+/// Ties an emitter to a particle of the simulation, it moves along and passes its velocity on to the smoke.
+#[derive(Clone, Copy)]
+pub struct Attachment {
+    pub particle: usize,
+    pub offset: glam::Vec2,
+    /// Fraction of the particle velocity new smoke starts with
+    pub inherit_velocity: f32,
 }
 
 pub struct SmokeGenerator {
     pub x: f32,
     pub y: f32,
-    pub particles: Vec<SmokeParticle>,
+    pub config: EmitterConfig,
+    /// Emits only while active, particles already out keep going
+    pub active: bool,
+    pub attachment: Option<Attachment>,
+    /// Velocity of what the emitter is attached to, pixels per second
+    velocity: glam::Vec2,
+    pub particles: SmokePool,
+    /// Fraction of a particle or time towards the next burst left over from the last update
+    emit_timer: f32,
+    /// Seconds since the generator was made, drives the turbulence
+    time: f32,
+    /// Updates since the generator was made, picks which particles sample the turbulence
+    frame: usize,
 }
 
-fn lerp(a: f32, b: f32, t: f32) -> f32 {
-    return a + (b - a) * t;
+fn lerp_color(a: Color, b: Color, t: f32) -> Color {
+    Color::from_rgba(lerp(a.r(), b.r(), t), lerp(a.g(), b.g(), t), lerp(a.b(), b.b(), t), lerp(a.a(), b.a(), t))
 }
 
-fn dumbpow(num: f32, pow: i32) -> f32 {
-    let mut pownum = num;
-    for _ in 1..pow { pownum *= num }
-    pownum
+fn lerp_color_hsv(a: Color, b: Color, t: f32) -> Color {
+    let (hue_a, saturation_a, value_a) = rgb_to_hsv(a.r(), a.g(), a.b());
+    let (hue_b, saturation_b, value_b) = rgb_to_hsv(b.r(), b.g(), b.b());
+    // Greys have no hue, take the other one so they don't drag the color around the wheel
+    let hue_a = if saturation_a == 0.0 { hue_b } else { hue_a };
+    let hue_b = if saturation_b == 0.0 { hue_a } else { hue_b };
+    // The short way around the wheel
+    let hue_diff = (hue_b - hue_a + 0.5).rem_euclid(1.0) - 0.5;
+    let hue = (hue_a + hue_diff * t).rem_euclid(1.0);
+    let (red, green, blue) = hsv_to_rgb(hue, lerp(saturation_a, saturation_b, t), lerp(value_a, value_b, t));
+    Color::from_rgba(red, green, blue, lerp(a.a(), b.a(), t))
 }
 
-fn smoothstop(a: f32, b: f32, t: f32) -> f32 {
-    a + (b - a) * (1.0 - dumbpow(1.0-t, 2))
-}
+impl SmokePool {
+    pub fn with_capacity(capacity: usize) -> Self {
+        let floats = || Vec::with_capacity(capacity);
+        let vectors = || Vec::with_capacity(capacity);
+        SmokePool {
+            capacity,
+            time_alive: floats(),
+            time_to_live: floats(),
+            start_pos: vectors(),
+            end_pos: vectors(),
+            start_angle: floats(),
+            end_angle: floats(),
+            start_size: floats(),
+            end_size: floats(),
+            start_opacity: floats(),
+            end_opacity: floats(),
+            pos: vectors(),
+            velocity: vectors(),
+            drift: vectors(),
+            swirl: vectors(),
+            tint: Vec::with_capacity(capacity),
+            depth: floats(),
+            softness: floats(),
+            start_frame: floats(),
+        }
+    }
 
-impl SmokeParticle {
-    pub fn get_pos(&self) -> (f32, f32) {
-        let t = 1f32 - (self.time_to_live - self.time_alive) / self.time_to_live;
-        (
-            smoothstop(self.start_state.x, self.end_state.x, t), 
-            smoothstop(self.start_state.y, self.end_state.y, t)
-        )
+    pub fn len(&self) -> usize {
+        self.pos.len()
     }
 
-    fn get_t(&self) -> f32 {
-        1f32 - (self.time_to_live - self.time_alive) / self.time_to_live
+    pub fn is_empty(&self) -> bool {
+        self.pos.is_empty()
     }
 
-    pub fn get_size(&self) -> f32 {
-        let t = self.get_t();
-        smoothstop(self.start_state.size, self.end_state.size, t)
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.capacity
     }
 
-    pub fn get_opacity(&self) -> f32 {
-        let t = self.get_t();
-        smoothstop(self.start_state.opacity, self.end_state.opacity, t)
+    pub fn positions(&self) -> &[glam::Vec2] {
+        &self.pos
     }
 
-    pub fn get_angle(&self) -> f32 {
-        let t = self.get_t();
-        smoothstop(self.start_state.angle, self.end_state.angle, t)
+    /// Returns false when there was no room for the particle.
+    fn push(&mut self, particle: NewParticle) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.time_alive.push(0.0);
+        self.time_to_live.push(particle.time_to_live);
+        self.start_pos.push(particle.start_state.pos);
+        self.end_pos.push(particle.end_state.pos);
+        self.start_angle.push(particle.start_state.angle);
+        self.end_angle.push(particle.end_state.angle);
+        self.start_size.push(particle.start_state.size);
+        self.end_size.push(particle.end_state.size);
+        self.start_opacity.push(particle.start_state.opacity);
+        self.end_opacity.push(particle.end_state.opacity);
+        self.pos.push(particle.start_state.pos);
+        self.velocity.push(particle.velocity);
+        self.drift.push(glam::Vec2::ZERO);
+        self.swirl.push(particle.swirl);
+        self.tint.push(particle.tint);
+        self.depth.push(particle.depth);
+        self.softness.push(0.0);
+        self.start_frame.push(particle.start_frame);
+        true
     }
 
+    /// Moves the last particle into the place of this one.
+    fn swap_remove(&mut self, i: usize) {
+        self.time_alive.swap_remove(i);
+        self.time_to_live.swap_remove(i);
+        self.start_pos.swap_remove(i);
+        self.end_pos.swap_remove(i);
+        self.start_angle.swap_remove(i);
+        self.end_angle.swap_remove(i);
+        self.start_size.swap_remove(i);
+        self.end_size.swap_remove(i);
+        self.start_opacity.swap_remove(i);
+        self.end_opacity.swap_remove(i);
+        self.pos.swap_remove(i);
+        self.velocity.swap_remove(i);
+        self.drift.swap_remove(i);
+        self.swirl.swap_remove(i);
+        self.tint.swap_remove(i);
+        self.depth.swap_remove(i);
+        self.softness.swap_remove(i);
+        self.start_frame.swap_remove(i);
+    }
 
-    pub fn alive(&self) -> bool {
-        self.time_to_live > self.time_alive
+    /// How far through its life the particle is, 0 at spawn and 1 at death.
+    fn get_t(&self, i: usize) -> f32 {
+        self.time_alive[i] / self.time_to_live[i]
+    }
+
+    /// Where a tweened particle would be without turbulence.
+    fn get_tween_pos(&self, i: usize, easing: &Easing) -> glam::Vec2 {
+        let t = easing.apply(self.get_t(i));
+        self.start_pos[i] + (self.end_pos[i] - self.start_pos[i]) * t
+    }
+
+    pub fn get_size(&self, i: usize, easing: &Easing) -> f32 {
+        easing.interpolate(self.start_size[i], self.end_size[i], self.get_t(i))
+    }
+
+    pub fn get_opacity(&self, i: usize, easing: &Easing) -> f32 {
+        easing.interpolate(self.start_opacity[i], self.end_opacity[i], self.get_t(i))
+    }
+
+    pub fn get_angle(&self, i: usize, easing: &Easing) -> f32 {
+        easing.interpolate(self.start_angle[i], self.end_angle[i], self.get_t(i))
+    }
+
+    pub fn get_color(&self, i: usize, easing: &Easing, gradient: &ColorGradient) -> Color {
+        let color = gradient.sample(easing.apply(self.get_t(i)));
+        let tint = self.tint[i];
+        Color::from_rgba(color.r() * tint.r(), color.g() * tint.g(), color.b() * tint.b(), color.a() * tint.a())
+    }
+
+    /// Frame of the sprite sheet to show, the fraction is how far it is towards the next one.
+    pub fn get_frame(&self, i: usize, sheet: &SpriteSheet, mode: &FrameMode) -> f32 {
+        let frames = sheet.frames.max(1) as f32;
+        let played = match *mode {
+            FrameMode::Still => 0.0,
+            FrameMode::Flipbook { frames_per_second, looping } => {
+                let played = self.time_alive[i] * frames_per_second;
+                if looping { played } else { played.min(frames - 1.0 - self.start_frame[i]).max(0.0) }
+            },
+            FrameMode::OverLifetime => self.get_t(i).min(1.0) * (frames - 1.0),
+        };
+        (self.start_frame[i] + played).rem_euclid(frames)
+    }
+
+    fn physics_step(&mut self, i: usize, dt: f32, physics: &SmokePhysics, simulation: &ParticleSimulation) {
+        let turbulence = on_disk(physics.turbulence);
+        let acc = simulation.get_acceleration_at(self.pos[i]) + glam::Vec2::new(0.0, -physics.buoyancy) + turbulence;
+        let mut velocity = (self.velocity[i] + acc * dt) * (-physics.drag * dt).exp();
+        let mut pos = self.pos[i] + velocity * dt;
+
+        // Smoke slides along whatever it runs into
+        for collider in simulation.colliders() {
+            let (distance, normal) = collider.get_distance(pos);
+            if distance < 0.0 {
+                pos -= normal * distance;
+                velocity -= normal * velocity.dot(normal).min(0.0);
+            }
+        }
+        self.pos[i] = pos;
+        self.velocity[i] = velocity;
+    }
+
+    /// Fades particles in as they leave their spawn point and out as they get close to a collider,
+    /// so the flat quads don't show hard edges where they meet things.
+    fn update_softness(&mut self, emitter_fade: f32, collider_fade: f32, colliders: &[Collider]) {
+        for i in 0..self.len() {
+            let mut softness = 1.0;
+            if emitter_fade > 0.0 {
+                softness *= (self.pos[i].distance(self.start_pos[i]) / emitter_fade).min(1.0);
+            }
+            if collider_fade > 0.0 {
+                for collider in colliders {
+                    softness *= (collider.get_distance(self.pos[i]).0 / collider_fade).clamp(0.0, 1.0);
+                }
+            }
+            self.softness[i] = softness;
+        }
     }
-}
 
-fn gen_range_float(a: f32, b: f32) -> f32 {
-    a + random::<f32>() % (b - a)
+    /// Indices of the particles with depth in (nearest, furthest], furthest first.
+    fn get_draw_order(&self, nearest: f32, furthest: f32) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.len()).filter(|&i| self.depth[i] > nearest && self.depth[i] <= furthest).collect();
+        order.sort_by(|&a, &b| self.depth[b].total_cmp(&self.depth[a]));
+        order
+    }
+
+    fn remove_dead(&mut self) {
+        // Backwards, so whatever gets swapped in has already been checked
+        for i in (0..self.len()).rev() {
+            if self.time_alive[i] >= self.time_to_live[i] {
+                self.swap_remove(i);
+            }
+        }
+    }
 }
 
 impl SmokeGenerator {
-    pub fn spawn_particle(&mut self, target_x: f32, target_y: f32) {
-        let time_to_live = 3f32;
+    pub fn new(x: f32, y: f32, config: EmitterConfig) -> Self {
+        let particles = SmokePool::with_capacity(config.capacity);
+        SmokeGenerator { x, y, config, active: true, attachment: None, velocity: glam::Vec2::ZERO, particles, emit_timer: 0.0, time: 0.0, frame: 0 }
+    }
 
-        const MIN_END_SIZE: f32 = 200.0;
-        const MAX_END_SIZE: f32 = 600.0;
-        const MIN_START_SIZE: f32 = 50.0;
-        const MAX_START_SIZE: f32 = 100.0;
+    pub fn attach(&mut self, particle: usize, offset: glam::Vec2) {
+        self.attachment = Some(Attachment { particle, offset, inherit_velocity: 1.0 });
+    }
 
-        const START_OPACITY: f32 = 0.1;
-        const END_OPACITY: f32 = 0.0;
-        
+    pub fn detach(&mut self) {
+        self.attachment = None;
+        self.velocity = glam::Vec2::ZERO;
+    }
 
-        let start_size = gen_range_float(MIN_START_SIZE, MAX_START_SIZE);
-        let start_angle = gen_range_float(-PI, PI);
+    /// Follows the particle the emitter is attached to.
+    fn follow_attachment(&mut self, simulation: &ParticleSimulation) {
+        let Some(attachment) = self.attachment else { return };
+        let pos = simulation.particles[attachment.particle].pos + attachment.offset;
+        (self.x, self.y) = (pos.x, pos.y);
+        self.velocity = simulation.get_velocity(attachment.particle) * attachment.inherit_velocity;
+    }
+
+    pub fn spawn_particle(&mut self) {
+        if self.particles.is_full() {
+            return;
+        }
+        let config = &self.config;
+        let time_to_live = config.lifetime.sample();
+        let start = glam::Vec2::new(self.x, self.y) + config.shape.sample();
+        let jitter = glam::Vec2::new(uniform(-config.spread, config.spread), uniform(-config.spread, config.spread));
+        // Easing out from the emitter velocity to a stop covers about half of it times the life
+        let end = start + config.travel + jitter + self.velocity * time_to_live / 2.0;
+
+        let start_angle = config.rotation.sample();
         let start_state = ParticleState {
-            x: self.x,
-            y: self.y,
+            pos: start,
             angle: start_angle,
-            size: start_size,
-            opacity: START_OPACITY,
+            size: config.start_size.sample(),
+            opacity: config.start_opacity.sample(),
         };
-        
-        const DIST: i32 = 200;
-        let target_x = target_x + thread_rng().gen_range(-DIST..=DIST) as f32;
-        let target_y = target_y + thread_rng().gen_range(-DIST..=DIST) as f32;
-
-        let end_angle = gen_range_float(-PI, PI);
-        let end_size:f32 = gen_range_float(MIN_END_SIZE, MAX_END_SIZE);
         let end_state = ParticleState {
-            x: target_x,
-            y: target_y,
-            angle: end_angle,
-            size: end_size,
-            opacity: END_OPACITY,
+            pos: end,
+            angle: start_angle + config.spin.sample(),
+            size: config.end_size.sample(),
+            opacity: config.end_opacity.sample(),
         };
+        let tint = lerp_color(config.tint.0, config.tint.1, unit());
+        let velocity = match config.motion {
+            SmokeMotion::Tween => glam::Vec2::ZERO,
+            SmokeMotion::Physical(physics) => glam::Vec2::from_angle(physics.direction.sample()) * physics.speed.sample() + self.velocity,
+        };
+
+        let depth = config.depth.sample();
+        let start_frame = match config.start_frame {
+            Some(frame) => frame.min(config.sheet.frames.max(1) - 1) as f32,
+            None => uniform(0.0, config.sheet.frames as f32).floor(),
+        };
+
+        let swirl = self.config.turbulence.map_or(glam::Vec2::ZERO, |turbulence| turbulence.get_velocity(start_state.pos, self.time));
+        self.particles.push(NewParticle { time_to_live, start_state, end_state, velocity, swirl, tint, depth, start_frame });
+    }
+
+    pub fn burst(&mut self, count: usize) {
+        for _ in 0..count {
+            self.spawn_particle();
+        }
+    }
 
-        self.particles.push( SmokeParticle { time_alive: 0f32, time_to_live, start_state, end_state} );
+    /// Emits whatever is due for this frame, ages and moves the particles.
+    /// Physical smoke feels the force fields and colliders of the simulation.
+    pub fn update(&mut self, dt: f32, simulation: &ParticleSimulation) {
+        self.follow_attachment(simulation);
+        self.update_particles(dt, simulation);
+        if !self.active {
+            return;
+        }
+        match self.config.emission {
+            Emission::Continuous(rate) => {
+                self.emit_timer += dt * rate;
+                let count = self.emit_timer.floor();
+                self.emit_timer -= count;
+                self.burst(count as usize);
+            },
+            Emission::Burst { count, interval } => {
+                self.emit_timer -= dt;
+                if self.emit_timer <= 0.0 {
+                    self.emit_timer = interval;
+                    self.burst(count);
+                }
+            },
+        }
     }
 
-    pub fn update_particles(&mut self, dt: f32) {
-        for particle in &mut self.particles {
-            particle.time_alive += dt;
+    /// Carries the particles along a flow, like the velocity of a fluid grid, for this frame.
+    pub fn follow_flow(&mut self, flow: impl Fn(glam::Vec2) -> glam::Vec2, dt: f32) {
+        let particles = &mut self.particles;
+        for (drift, pos) in particles.drift.iter_mut().zip(&particles.pos) {
+            *drift += flow(*pos) * dt;
+        }
+    }
+
+    pub fn update_particles(&mut self, dt: f32, simulation: &ParticleSimulation) {
+        self.time += dt;
+        self.frame += 1;
+        let particles = &mut self.particles;
+        particles.time_alive.iter_mut().for_each(|time_alive| *time_alive += dt);
+        if let Some(turbulence) = &self.config.turbulence {
+            let refresh = turbulence.refresh.max(1);
+            for i in (self.frame % refresh..particles.len()).step_by(refresh) {
+                particles.swirl[i] = turbulence.get_velocity(particles.pos[i], self.time);
+            }
+            for (drift, swirl) in particles.drift.iter_mut().zip(&particles.swirl) {
+                *drift += *swirl * dt;
+            }
+        }
+        match self.config.motion {
+            SmokeMotion::Tween => {
+                for i in 0..particles.len() {
+                    particles.pos[i] = particles.get_tween_pos(i, &self.config.easing.position) + particles.drift[i];
+                }
+            },
+            SmokeMotion::Physical(physics) => {
+                for (pos, drift) in particles.pos.iter_mut().zip(particles.drift.iter_mut()) {
+                    *pos += *drift;
+                    *drift = glam::Vec2::ZERO;
+                }
+                for i in 0..particles.len() {
+                    particles.physics_step(i, dt, &physics, simulation);
+                }
+            },
+        }
+        particles.update_softness(self.config.emitter_fade, self.config.collider_fade, simulation.colliders());
+        particles.remove_dead();
+    }
+
+    /// Draws all particles, back to front.
+    pub fn display(&self, renderer: &mut dyn Renderer, texture: &Texture) {
+        self.display_between(renderer, texture, f32::NEG_INFINITY, f32::INFINITY);
+    }
+
+    /// Draws particles with depth in (nearest, furthest], back to front,
+    /// so they can be slotted in between the layers of a forest.
+    pub fn display_between(&self, renderer: &mut dyn Renderer, texture: &Texture, nearest: f32, furthest: f32) {
+        let config = &self.config;
+        let easing = &config.easing;
+        let particles = &self.particles;
+        for i in particles.get_draw_order(nearest, furthest) {
+            let pos = particles.pos[i];
+            let size = particles.get_size(i, &easing.size);
+            let quad = get_rotated_quad(pos.x, pos.y, size, size, particles.get_angle(i, &easing.angle));
+            let tint = particles.get_color(i, &easing.color, &self.config.gradient);
+            let opacity = tint.a() * particles.get_opacity(i, &easing.opacity) * particles.softness[i];
+
+            let frame = particles.get_frame(i, &config.sheet, &config.frame_mode);
+            let frames = config.sheet.frames.max(1);
+            let current = (frame.floor() as u32).min(frames - 1);
+            let blend = if config.frame_blending && frames > 1 { frame.fract() } else { 0.0 };
+            let color = Color::from_rgba(tint.r(), tint.g(), tint.b(), opacity * (1.0 - blend));
+            renderer.draw_textured_quad(quad, [color; 4], config.sheet.get_frame_coords(current), texture, config.blend);
+            if blend > 0.0 {
+                let next = (current + 1) % frames;
+                let color = Color::from_rgba(tint.r(), tint.g(), tint.b(), opacity * blend);
+                renderer.draw_textured_quad(quad, [color; 4], config.sheet.get_frame_coords(next), texture, config.blend);
+            }
         }
-        self.particles.retain(|x| { x.alive() });
     }
 }
 
+/// Loads a sprite or sprite sheet from disk, should be done once and the texture kept around.
+pub fn load_texture<P: AsRef<Path>>(path: P) -> Result<Texture, String> {
+    Texture::load(path)
+}
+
+/// Decodes the bundled smoke sprite, should be done once and the texture kept around.
+pub fn get_smoke_texture() -> Texture {
+    Texture::from_file_bytes(SMOKE_TEXTURE).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
 
-/*
- * this is commented out code for drawing particles
-        for particle in &generator.particles {
-            let (cx, cy) = particle.get_pos();
-            let p_size: f32 = particle.get_size();
-            let opacity = particle.get_opacity();
-            let angle = particle.get_angle();
+    /// Average time to update a generator kept full at `count` particles, over `frames` frames of 1/60 s.
+    fn benchmark(config: EmitterConfig, count: usize, frames: usize) -> Duration {
+        const DT: f32 = 1.0 / 60.0;
+        let simulation = ParticleSimulation::new();
+        let mut generator = SmokeGenerator::new(640.0, 360.0, EmitterConfig { capacity: count, ..config });
+        generator.active = false;
+        generator.burst(count);
 
-            let quad = get_rotated_quad(cx, cy, p_size, p_size, angle);
-            let color = Color::from_rgba(1.0, 1.0, 1.0, opacity);
-            let colors: [Color; 4] = [color; 4];
-            let image_cords = get_rotated_quad(0.0, 0.0, 2.0, 2.0, 0.0);
-            graphics.draw_quad_image_tinted_four_color(quad, colors, image_cords, smoke_texture);
+        let start = Instant::now();
+        for _ in 0..frames {
+            generator.update(DT, &simulation);
+            generator.burst(count - generator.particles.len());
         }
+        start.elapsed() / frames as u32
+    }
 
-fn get_smoke_texture(graphics: &mut Graphics2D) {
-        let image = graphics.create_image_from_file_path(None, speedy2d::image::ImageSmoothingMode::NearestNeighbor, Path::new("./smoke_particle.png")).unwrap();
+    /// Run with `cargo test --release bench_smoke -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_smoke() {
+        const PARTICLES: usize = 50_000;
+        const FRAMES: usize = 120;
+        const FRAME_BUDGET: f32 = 1000.0 / 60.0;
+        let physical = EmitterConfig { motion: SmokeMotion::Physical(SmokePhysics::default()), ..Default::default() };
+        let turbulent = EmitterConfig { turbulence: Some(CurlNoise::default()), ..physical.clone() };
+        for (name, config) in [("tween", EmitterConfig::default()), ("physical", physical), ("physical + curl noise", turbulent)] {
+            let frame_time = benchmark(config, PARTICLES, FRAMES).as_secs_f32() * 1000.0;
+            println!("{name}: {PARTICLES} particles, {frame_time:.2} ms per frame ({:.0}% of {FRAME_BUDGET:.1} ms)", frame_time / FRAME_BUDGET * 100.0);
+        }
+    }
 }
-*/
@@ -0,0 +1,293 @@
+use glam::Vec2;
+use speedy2d::color::Color;
+
+use crate::renderer::*;
+
+/*
+ * Draws into an RGBA buffer on the CPU, for saving frames without a window.
+ * Quads are split into two triangles and filled with the top-left rule, so pixels on a shared
+ * edge get drawn once and see-through shapes don't show seams.
+ * This is the only renderer that really blends additively, see `BlendMode::Additive`.
+ */
+
+/// How many pixels wide the soft edge of a circle is.
+const CIRCLE_EDGE: f32 = 1.0;
+
+pub struct SoftwareRenderer {
+    pub width: u32,
+    pub height: u32,
+    /// Pixels per scene unit, lets the same scene fill a bigger picture
+    pub scale: f32,
+    /// Linear 0..1 RGBA, additive blending can push color above 1 until it's read out
+    pixels: Vec<[f32; 4]>,
+}
+
+/// Colors and texture coordinates at the corners of a triangle, interpolated across it.
+struct Vertex {
+    pos: Vec2,
+    color: [f32; 4],
+    coords: Vec2,
+}
+
+fn to_array(color: Color) -> [f32; 4] {
+    [color.r(), color.g(), color.b(), color.a()]
+}
+
+fn to_byte(c: f32) -> u8 {
+    (c.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+/// Twice the signed area of the triangle a, b, p. Positive when p is clockwise from a -> b on screen.
+fn edge(a: Vec2, b: Vec2, p: Vec2) -> f32 {
+    (b.x - a.x) * (p.y - a.y) - (b.y - a.y) * (p.x - a.x)
+}
+
+/// Whether a pixel center exactly on the edge a -> b belongs to the triangle, with clockwise winding.
+fn is_top_left(a: Vec2, b: Vec2) -> bool {
+    (a.y == b.y && b.x > a.x) || b.y < a.y
+}
+
+impl SoftwareRenderer {
+    pub fn new(width: u32, height: u32) -> Self {
+        SoftwareRenderer::with_scale(width, height, 1.0)
+    }
+
+    pub fn with_scale(width: u32, height: u32, scale: f32) -> Self {
+        SoftwareRenderer { width, height, scale, pixels: vec![[0.0; 4]; (width * height) as usize] }
+    }
+
+    pub fn get_pixel(&self, x: u32, y: u32) -> [f32; 4] {
+        self.pixels[(y * self.width + x) as usize].map(|c| c.min(1.0))
+    }
+
+    /// The picture as 8 bit RGBA, row by row from the top.
+    pub fn to_rgba8(&self) -> Vec<u8> {
+        self.pixels.iter().flat_map(|p| p.map(to_byte)).collect()
+    }
+
+    /// Averages every `factor` by `factor` block into one pixel, for drawing supersampled
+    /// and saving at the smaller size. Size has to divide evenly.
+    pub fn to_rgba8_downsampled(&self, factor: u32) -> Vec<u8> {
+        let (width, height) = (self.width / factor, self.height / factor);
+        let weight = 1.0 / (factor * factor) as f32;
+        let mut bytes = Vec::with_capacity((width * height * 4) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = [0.0; 4];
+                for sy in y * factor..(y + 1) * factor {
+                    for sx in x * factor..(x + 1) * factor {
+                        let p = self.get_pixel(sx, sy);
+                        for k in 0..4 {
+                            sum[k] += p[k];
+                        }
+                    }
+                }
+                bytes.extend(sum.map(|c| to_byte(c * weight)));
+            }
+        }
+        bytes
+    }
+
+    fn blend_pixel(&mut self, x: u32, y: u32, color: [f32; 4], blend: BlendMode) {
+        let alpha = color[3];
+        if alpha <= 0.0 {
+            return;
+        }
+        let dst = &mut self.pixels[(y * self.width + x) as usize];
+        match blend {
+            BlendMode::Alpha => {
+                for k in 0..3 {
+                    dst[k] = color[k] * alpha + dst[k] * (1.0 - alpha);
+                }
+                dst[3] = alpha + dst[3] * (1.0 - alpha);
+            },
+            BlendMode::Additive => {
+                for k in 0..3 {
+                    dst[k] += color[k] * alpha;
+                }
+                dst[3] = (dst[3] + alpha).min(1.0);
+            },
+        }
+    }
+
+    /// Pixels whose centers fall inside the box, clipped to the buffer.
+    fn get_pixel_range(&self, min: Vec2, max: Vec2) -> Option<(u32, u32, u32, u32)> {
+        let x0 = (min.x - 0.5).ceil().max(0.0);
+        let y0 = (min.y - 0.5).ceil().max(0.0);
+        let x1 = (max.x - 0.5).floor().min(self.width as f32 - 1.0);
+        let y1 = (max.y - 0.5).floor().min(self.height as f32 - 1.0);
+        if x0 > x1 || y0 > y1 {
+            return None;
+        }
+        Some((x0 as u32, y0 as u32, x1 as u32, y1 as u32))
+    }
+
+    fn fill_triangle(&mut self, vertices: [&Vertex; 3], texture: Option<&Texture>, blend: BlendMode) {
+        let [v0, mut v1, mut v2] = vertices;
+        let mut area = edge(v0.pos, v1.pos, v2.pos);
+        if area == 0.0 {
+            return;
+        }
+        if area < 0.0 {
+            std::mem::swap(&mut v1, &mut v2);
+            area = -area;
+        }
+        let min = v0.pos.min(v1.pos).min(v2.pos);
+        let max = v0.pos.max(v1.pos).max(v2.pos);
+        let Some((x0, y0, x1, y1)) = self.get_pixel_range(min, max) else { return };
+        let inclusive = [is_top_left(v1.pos, v2.pos), is_top_left(v2.pos, v0.pos), is_top_left(v0.pos, v1.pos)];
+
+        let edges = [(v1.pos, v2.pos), (v2.pos, v0.pos), (v0.pos, v1.pos)];
+
+        for y in y0..=y1 {
+            // Thin slanted shapes cover a small part of their box, only look near the covered span.
+            // Every edge function is linear along the row, so each one bounds it from one side.
+            let center_y = y as f32 + 0.5;
+            let (mut left, mut right) = (x0 as f32, x1 as f32);
+            for (a, b) in edges {
+                let slope = a.y - b.y;
+                let crossing = a.x + (b.x - a.x) * (center_y - a.y) / (b.y - a.y) - 0.5;
+                if slope > 0.0 {
+                    left = left.max(crossing.floor());
+                } else if slope < 0.0 {
+                    right = right.min(crossing.ceil());
+                }
+            }
+            if left > right {
+                continue;
+            }
+            for x in left as u32..=right as u32 {
+                let p = Vec2::new(x as f32 + 0.5, center_y);
+                let weights = [edge(v1.pos, v2.pos, p), edge(v2.pos, v0.pos, p), edge(v0.pos, v1.pos, p)];
+                let inside = weights.iter().zip(inclusive).all(|(&w, inclusive)| w > 0.0 || (w == 0.0 && inclusive));
+                if !inside {
+                    continue;
+                }
+                let [w0, w1, w2] = weights.map(|w| w / area);
+                let mut color: [f32; 4] = std::array::from_fn(|k| v0.color[k] * w0 + v1.color[k] * w1 + v2.color[k] * w2);
+                if let Some(texture) = texture {
+                    let texel = texture.sample(v0.coords * w0 + v1.coords * w1 + v2.coords * w2);
+                    for k in 0..4 {
+                        color[k] *= texel[k];
+                    }
+                }
+                self.blend_pixel(x, y, color, blend);
+            }
+        }
+    }
+
+    fn fill_quad(&mut self, corners: [Vec2; 4], colors: [Color; 4], image_coords: [Vec2; 4], texture: Option<&Texture>, blend: BlendMode) {
+        let vertices: [Vertex; 4] = std::array::from_fn(|i| Vertex { pos: corners[i], color: to_array(colors[i]), coords: image_coords[i] });
+        self.fill_triangle([&vertices[0], &vertices[1], &vertices[2]], texture, blend);
+        self.fill_triangle([&vertices[0], &vertices[2], &vertices[3]], texture, blend);
+    }
+}
+
+impl Renderer for SoftwareRenderer {
+    fn clear(&mut self, color: Color) {
+        self.pixels.fill(to_array(color));
+    }
+
+    fn draw_circle(&mut self, center: Vec2, radius: f32, color: Color) {
+        let (center, radius) = (center * self.scale, radius * self.scale);
+        let extent = Vec2::splat(radius + CIRCLE_EDGE);
+        let Some((x0, y0, x1, y1)) = self.get_pixel_range(center - extent, center + extent) else { return };
+        let color = to_array(color);
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                let distance = Vec2::new(x as f32 + 0.5, y as f32 + 0.5).distance(center);
+                let coverage = ((radius - distance) / CIRCLE_EDGE + 0.5).clamp(0.0, 1.0);
+                if coverage > 0.0 {
+                    self.blend_pixel(x, y, [color[0], color[1], color[2], color[3] * coverage], BlendMode::Alpha);
+                }
+            }
+        }
+    }
+
+    fn draw_line(&mut self, start: Vec2, end: Vec2, thickness: f32, color: Color) {
+        let side = (end - start).perp().normalize_or_zero() * thickness / 2.0;
+        // Corners get scaled by draw_quad, so thickness scales with them
+        self.draw_quad([start + side, end + side, end - side, start - side], [color; 4]);
+    }
+
+    fn draw_quad(&mut self, corners: [Vec2; 4], colors: [Color; 4]) {
+        self.fill_quad(corners.map(|p| p * self.scale), colors, [Vec2::ZERO; 4], None, BlendMode::Alpha);
+    }
+
+    fn draw_textured_quad(&mut self, corners: [Vec2; 4], colors: [Color; 4], image_coords: [Vec2; 4], texture: &Texture, blend: BlendMode) {
+        self.fill_quad(corners.map(|p| p * self.scale), colors, image_coords, Some(texture), blend);
+    }
+
+    fn get_visible_area(&self) -> (Vec2, Vec2) {
+        (Vec2::ZERO, Vec2::new(self.width as f32, self.height as f32) / self.scale)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HALF_WHITE: Color = Color::from_rgba(1.0, 1.0, 1.0, 0.5);
+
+    fn alphas(renderer: &SoftwareRenderer) -> Vec<f32> {
+        (0..renderer.height).flat_map(|y| (0..renderer.width).map(move |x| (x, y))).map(|(x, y)| renderer.get_pixel(x, y)[3]).collect()
+    }
+
+    #[test]
+    fn quad_covers_pixels_inside_it() {
+        let mut renderer = SoftwareRenderer::new(8, 8);
+        renderer.draw_rectangle(Vec2::new(2.0, 2.0), Vec2::new(6.0, 5.0), Color::WHITE);
+        for y in 0..8 {
+            for x in 0..8 {
+                let inside = (2..6).contains(&x) && (2..5).contains(&y);
+                assert_eq!(renderer.get_pixel(x, y)[3], if inside { 1.0 } else { 0.0 }, "pixel {x}, {y}");
+            }
+        }
+    }
+
+    #[test]
+    fn shared_edges_are_drawn_once() {
+        // Diagonal inside the quad and the edges between the quads go right through pixel centers
+        let mut renderer = SoftwareRenderer::new(8, 8);
+        renderer.draw_rectangle(Vec2::ZERO, Vec2::new(4.5, 4.5), HALF_WHITE);
+        renderer.draw_rectangle(Vec2::new(4.5, 0.0), Vec2::new(8.0, 4.5), HALF_WHITE);
+        renderer.draw_rectangle(Vec2::new(0.0, 4.5), Vec2::new(4.5, 8.0), HALF_WHITE);
+        renderer.draw_rectangle(Vec2::new(4.5, 4.5), Vec2::new(8.0, 8.0), HALF_WHITE);
+        // Drawn twice would be 0.75
+        assert!(alphas(&renderer).iter().all(|&alpha| (alpha - 0.5).abs() < 1e-4));
+    }
+
+    #[test]
+    fn circle_covers_its_area() {
+        let mut renderer = SoftwareRenderer::new(32, 32);
+        renderer.draw_circle(Vec2::splat(16.0), 8.0, Color::WHITE);
+        assert_eq!(renderer.get_pixel(16, 16)[3], 1.0);
+        assert_eq!(renderer.get_pixel(16, 4)[3], 0.0);
+        let area: f32 = alphas(&renderer).iter().sum();
+        assert!((area - std::f32::consts::PI * 64.0).abs() < 1.0, "area {area}");
+    }
+
+    #[test]
+    fn additive_adds_and_alpha_covers() {
+        let mut renderer = SoftwareRenderer::new(2, 1);
+        renderer.clear(Color::from_rgba(0.2, 0.2, 0.2, 1.0));
+        renderer.blend_pixel(0, 0, [1.0, 0.0, 0.0, 0.5], BlendMode::Alpha);
+        renderer.blend_pixel(1, 0, [1.0, 0.0, 0.0, 0.5], BlendMode::Additive);
+        let close = |a: [f32; 4], b: [f32; 4]| a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-6);
+        assert!(close(renderer.get_pixel(0, 0), [0.6, 0.1, 0.1, 1.0]));
+        assert!(close(renderer.get_pixel(1, 0), [0.7, 0.2, 0.2, 1.0]));
+
+        // Additive light saturates when read out
+        renderer.blend_pixel(1, 0, [1.0, 1.0, 1.0, 1.0], BlendMode::Additive);
+        assert!(close(renderer.get_pixel(1, 0), [1.0, 1.0, 1.0, 1.0]));
+    }
+
+    #[test]
+    fn downsampling_averages_blocks() {
+        let mut renderer = SoftwareRenderer::new(4, 2);
+        renderer.draw_rectangle(Vec2::ZERO, Vec2::new(2.0, 2.0), Color::WHITE);
+        renderer.draw_rectangle(Vec2::new(2.0, 0.0), Vec2::new(3.0, 2.0), Color::WHITE);
+        assert_eq!(renderer.to_rgba8_downsampled(2), vec![255, 255, 255, 255, 128, 128, 128, 128]);
+        assert_eq!(renderer.to_rgba8_downsampled(1), renderer.to_rgba8());
+    }
+}
@@ -0,0 +1,132 @@
+use crate::easing::*;
+
+/// Physical properties of a tree segment, what the simulation needs to know about it.
+pub struct NodePhysics {
+    pub mass: f32,
+    pub stiffness: f32,
+    pub bend_stiffness: f32,
+    pub damping: f32,
+}
+
+/// Traits shared by all trees of one kind.
+/// Stiffness values are in 0..1, 1 keeps the segment perfectly rigid.
+#[derive(Clone)]
+pub struct Species {
+    pub name: &'static str,
+
+    pub trunk_stiffness: f32,
+    pub twig_stiffness: f32,
+    pub trunk_bend_stiffness: f32,
+    pub twig_bend_stiffness: f32,
+    pub trunk_damping: f32,
+    pub twig_damping: f32,
+
+    /// How fast rigidity falls off with thinner wood, 1 is linear in width
+    pub width_exponent: f32,
+    /// Every branching level multiplies rigidity by this
+    pub level_falloff: f32,
+    /// How much rigidity young wood near the tips loses, 0 ignores age
+    pub age_softening: f32,
+    pub wood_density: f32,
+
+    pub trunk_width: f32,
+    pub segment_length: f32,
+    /// Degrees between a branch and the one it splits from
+    pub split_angle: f32,
+    /// Every segment is this much thinner than the previous one
+    pub width_coefficient: f32,
+    /// How strongly every segment turns towards the light
+    pub phototropism: f32,
+}
+
+impl Species {
+    pub const OAK: Species = Species {
+        name: "oak",
+        trunk_stiffness: 1.0,
+        twig_stiffness: 0.6,
+        trunk_bend_stiffness: 0.9,
+        twig_bend_stiffness: 0.05,
+        trunk_damping: 0.05,
+        twig_damping: 0.01,
+        width_exponent: 0.7,
+        level_falloff: 0.85,
+        age_softening: 0.3,
+        wood_density: 1.0,
+        trunk_width: 10.0,
+        segment_length: 40.0,
+        split_angle: 30.0,
+        width_coefficient: 0.90,
+        phototropism: 0.04,
+    };
+
+    pub const WILLOW: Species = Species {
+        name: "willow",
+        trunk_stiffness: 1.0,
+        twig_stiffness: 0.3,
+        trunk_bend_stiffness: 0.6,
+        twig_bend_stiffness: 0.005,
+        trunk_damping: 0.03,
+        twig_damping: 0.005,
+        width_exponent: 1.5,
+        level_falloff: 0.7,
+        age_softening: 0.6,
+        wood_density: 0.6,
+        trunk_width: 8.0,
+        segment_length: 34.0,
+        split_angle: 20.0,
+        width_coefficient: 0.91,
+        phototropism: 0.01,
+    };
+
+    pub const PINE: Species = Species {
+        name: "pine",
+        trunk_stiffness: 1.0,
+        twig_stiffness: 0.8,
+        trunk_bend_stiffness: 0.95,
+        twig_bend_stiffness: 0.15,
+        trunk_damping: 0.08,
+        twig_damping: 0.02,
+        width_exponent: 0.5,
+        level_falloff: 0.9,
+        age_softening: 0.2,
+        wood_density: 0.8,
+        trunk_width: 8.0,
+        segment_length: 30.0,
+        split_angle: 45.0,
+        width_coefficient: 0.88,
+        phototropism: 0.08,
+    };
+
+    /// Maps a segment of the tree to its physical properties.
+    /// `age` is counted in segments from the furthest tip, so tips have age 0.
+    pub fn get_node_physics(&self, width: f32, level: u32, age: u32, max_width: f32, max_age: u32) -> NodePhysics {
+        let width_part = (width / max_width).clamp(0.0, 1.0).powf(self.width_exponent);
+        let level_part = self.level_falloff.powi(level as i32);
+        let age_part = 1.0 - self.age_softening * (1.0 - age as f32 / max_age.max(1) as f32);
+        let rigidity = width_part * level_part * age_part;
+
+        NodePhysics {
+            mass: self.wood_density * width * width,
+            stiffness: lerp(self.twig_stiffness, self.trunk_stiffness, rigidity),
+            bend_stiffness: lerp(self.twig_bend_stiffness, self.trunk_bend_stiffness, rigidity),
+            damping: lerp(self.twig_damping, self.trunk_damping, rigidity),
+        }
+    }
+}
+
+impl Species {
+    /// Same species, grown bigger or smaller.
+    pub fn scaled(&self, factor: f32) -> Species {
+        Species {
+            trunk_width: self.trunk_width * factor,
+            segment_length: self.segment_length * factor,
+            ..self.clone()
+        }
+    }
+}
+
+impl Default for Species {
+    fn default() -> Self {
+        Species::OAK
+    }
+}
@@ -1,11 +1,17 @@
 use glam::Vec2;
 use speedy2d::color::Color;
-use rand::{Rng, thread_rng};
 use core::f32;
 use std::f32::consts::PI;
-use speedy2d::Graphics2D;
+use serde::{Deserialize, Serialize};
 
 use crate::verlet_physics::*;
+use crate::render_helpers::*;
+use crate::renderer::*;
+use crate::camera::*;
+use crate::species::*;
+use crate::environment::*;
+use crate::seasons::*;
+use crate::distributions::*;
 
 
 struct Node {
@@ -15,11 +21,16 @@ struct Node {
     level: u32,
     parent: Option<usize>,
     pos: Vec2,
+    particle: Option<usize>,
+    underground: bool,
+    strahler: u32,
+    branch: usize,
+    age: u32,
 }
 
 impl Node {
-    fn derive_from(&self, self_id: usize) -> Self {
-        let width = self.width * WIDTH_COEFFICIENT;
+    fn derive_from(&self, self_id: usize, species: &Species) -> Self {
+        let width = self.width * species.width_coefficient;
         let angle = self.angle + get_angle_deviation();
         let length = self.length * LENGTH_COEFFICIENT;
 
@@ -31,25 +42,198 @@ impl Node {
             level: self.level,
             pos,
             parent: Some(self_id),
+            particle: None,
+            underground: self.underground,
+            strahler: 0,
+            branch: self.branch,
+            age: 0,
+        }
+    }
+
+    /// Bends the node towards the light and away from obstacles, then puts it after its parent.
+    /// Returns false if there is no room to grow there.
+    fn steer(&mut self, parent_pos: Vec2, environment: &Environment, species: &Species) -> bool {
+        let mut direction = Vec2::from_angle(self.angle) + environment.light_direction * species.phototropism;
+        let ahead = parent_pos + direction.normalize_or_zero() * self.length;
+        direction += environment.get_repulsion(ahead, self.length * AVOIDANCE_RANGE) * AVOIDANCE_STRENGTH;
+        if direction == Vec2::ZERO { return false; }
+
+        self.angle = direction.y.atan2(direction.x);
+        self.pos = parent_pos + Vec2::from_angle(self.angle) * self.length;
+        !environment.is_blocked(self.pos, self.width / 2.0)
+    }
+
+    fn derive_root_from(&self, self_id: usize, extra_angle: f32) -> Self {
+        let mut angle = self.angle + get_angle_deviation() + extra_angle;
+        // Gravitropism, roots slowly turn towards straight down
+        angle += (ROOT_SYSTEM_DOWN - angle) * ROOT_SYSTEM_GRAVITROPISM;
+        // Never break through the ground
+        let min_angle = ROOT_SYSTEM_MIN_ANGLE.to_radians();
+        let angle = angle.clamp(min_angle, PI - min_angle);
+        let length = self.length * LENGTH_COEFFICIENT;
+
+        let pos = self.pos + Vec2::from_angle(angle)*length;
+        Node {
+            width: self.width * ROOT_SYSTEM_WIDTH_DECAY,
+            angle,
+            length,
+            level: self.level,
+            pos,
+            parent: Some(self_id),
+            particle: None,
+            underground: true,
+            strahler: 0,
+            branch: self.branch,
+            age: 0,
         }
     }
 
     fn get_bulked_points(&self) -> (Vec2, Vec2) {
-        let angle = self.angle + PI / 2.0;
-        let half_width = self.width / 2.0;
-        let a = self.pos - Vec2::from_angle(angle) * half_width;
-        let b = self.pos + Vec2::from_angle(angle) * half_width;
-        (a, b)
+        get_bulked_points(self.pos, self.angle, self.width)
     }
 }
 
+fn get_bulked_points(pos: Vec2, angle: f32, width: f32) -> (Vec2, Vec2) {
+    let angle = angle + PI / 2.0;
+    let half_width = width / 2.0;
+    let a = pos - Vec2::from_angle(angle) * half_width;
+    let b = pos + Vec2::from_angle(angle) * half_width;
+    (a, b)
+}
+
+fn get_bark_color(node_id: usize, underground: bool) -> Color {
+    // Cheap hash so the variation is stable between frames
+    let hash = (node_id as u32).wrapping_mul(2654435761) >> 16;
+    let variation = (hash % 256) as f32 / 255.0;
+    let (saturation, value) = if underground {
+        (ROOT_SYSTEM_SATURATION, ROOT_SYSTEM_VALUE)
+    } else {
+        (BARK_SATURATION, BARK_VALUE)
+    };
+    let (r, g, b) = hsv_to_rgb(BARK_HUE, saturation, value + variation * BARK_VALUE_VARIATION);
+    Color::from_rgb(r, g, b)
+}
+
+/// How much of the haze color covers things at the given depth.
+pub fn get_haze(depth: f32) -> f32 {
+    1.0 - (-depth * ATMOSPHERE_DENSITY).exp()
+}
+
+fn apply_haze(color: Color, haze: f32) -> Color {
+    let mix = |c: f32, h: f32| c + (h - c) * haze;
+    Color::from_rgb(mix(color.r(), HAZE_COLOR.r()), mix(color.g(), HAZE_COLOR.g()), mix(color.b(), HAZE_COLOR.b()))
+}
+
+/// Draws soil below the ground line.
+pub fn display_ground(renderer: &mut dyn Renderer) {
+    display_ground_at(renderer, GROUND_LEVEL, 0.0);
+}
+
+/// Draws soil below the given ground line across the whole view, faded by the depth of its layer.
+pub fn display_ground_at(renderer: &mut dyn Renderer, ground_level: f32, depth: f32) {
+    let haze = get_haze(depth);
+    let (min, max) = renderer.get_visible_area();
+    if ground_level > max.y { return; }
+    let (r, g, b) = hsv_to_rgb(BARK_HUE, SOIL_SATURATION, SOIL_VALUE);
+    renderer.draw_rectangle(Vec2::new(min.x, ground_level), max, apply_haze(Color::from_rgb(r, g, b), haze));
+
+    let (r, g, b) = hsv_to_rgb(GRASS_HUE, SOIL_SATURATION, GRASS_VALUE);
+    const GROUND_LINE_THICKNESS: f32 = 3.0;
+    renderer.draw_line(Vec2::new(min.x, ground_level), Vec2::new(max.x, ground_level), GROUND_LINE_THICKNESS, apply_haze(Color::from_rgb(r, g, b), haze));
+}
+
+fn segments_intersect(a1: Vec2, a2: Vec2, b1: Vec2, b2: Vec2) -> bool {
+    let (da, db) = (a2 - a1, b2 - b1);
+    let denominator = da.perp_dot(db);
+    if denominator == 0.0 { return false; }
+    let t = (b1 - a1).perp_dot(db) / denominator;
+    let u = (b1 - a1).perp_dot(da) / denominator;
+    (0.0..=1.0).contains(&t) && (0.0..=1.0).contains(&u)
+}
+
+fn svg_color(color: Color) -> String {
+    let to_byte = |c: f32| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+    format!("#{:02x}{:02x}{:02x}", to_byte(color.r()), to_byte(color.g()), to_byte(color.b()))
+}
+
+/// Node of a tree without any simulation state.
+#[derive(Serialize, Deserialize)]
+pub struct SkeletonNode {
+    pub pos: Vec2,
+    pub width: f32,
+    pub angle: f32,
+    pub length: f32,
+    pub level: u32,
+    pub parent: Option<usize>,
+    #[serde(default)]
+    pub underground: bool,
+}
+
+struct Leaf {
+    node: usize,
+    /// Relative to the node
+    angle: f32,
+    size: f32,
+    /// Leaves with smaller seeds grow first and fall last
+    seed: f32,
+    /// Shifts the season for this leaf, so leaves don't all turn at once
+    variation: f32,
+    on_branch: bool,
+}
+
+/// Leaf that fell off, it flutters down as a free particle.
+struct FallingLeaf {
+    particle: usize,
+    pos: Vec2,
+    angle: f32,
+    size: f32,
+    variation: f32,
+    phase: f32,
+    landed_at: Option<f32>,
+}
+
+fn display_leaf(renderer: &mut dyn Renderer, pos: Vec2, angle: f32, size: f32, color: Color) {
+    let direction = Vec2::from_angle(angle);
+    let side = direction.perp() * size * LEAF_WIDTH;
+    let middle = pos + direction * size * 0.5;
+    let tip = pos + direction * size;
+    renderer.draw_quad([pos, middle + side, tip, middle - side], [color; 4]);
+}
+
 pub struct Tree {
-    nodes: Vec<Node>
+    nodes: Vec<Node>,
+    branch_count: usize,
+    pub species: Species,
+    /// Distance from the viewer, 0 is the front layer. Far trees fade into the haze.
+    pub depth: f32,
+    /// Where the tree stands, fallen pieces and leaves stop there
+    pub ground_level: f32,
+    /// Lets snow pile up on branches in winter
+    pub snow: bool,
+    leaves: Vec<Leaf>,
+    falling_leaves: Vec<FallingLeaf>,
+    spare_leaf_particles: Vec<usize>,
+    season: f32,
+    snow_cover: f32,
+    time: f32,
 }
 
 impl Tree {
     fn new () -> Self {
-        Tree { nodes: Vec::new() }
+        Tree {
+            nodes: Vec::new(),
+            branch_count: 0,
+            species: Species::default(),
+            depth: 0.0,
+            ground_level: GROUND_LEVEL,
+            snow: false,
+            leaves: Vec::new(),
+            falling_leaves: Vec::new(),
+            spare_leaf_particles: Vec::new(),
+            season: SUMMER,
+            snow_cover: 0.0,
+            time: 0.0,
+        }
     }
 
     fn add_node(&mut self, node: Node) -> usize {
@@ -57,30 +241,623 @@ impl Tree {
         self.nodes.len()-1
     }
 
-    pub fn display(&self, graphics: &mut Graphics2D) {
+    fn get_children(&self) -> Vec<Vec<usize>> {
+        let mut children = vec![Vec::new(); self.nodes.len()];
+        for (i, node) in self.nodes.iter().enumerate() {
+            if let Some(parent) = node.parent {
+                children[parent].push(i);
+            }
+        }
+        children
+    }
+
+    /// Splits the tree into chains of nodes, each chain is one branch.
+    /// Branch continues into the widest child, other children start new branches.
+    /// Every chain except the trunk starts with the node it grows from.
+    fn get_branch_chains(&self) -> Vec<Vec<usize>> {
+        let children = self.get_children();
+        let mut chains = Vec::new();
+
+        for (i, node) in self.nodes.iter().enumerate() {
+            let is_chain_start = match node.parent {
+                None => true,
+                Some(parent) => {
+                    let widest = children[parent].iter()
+                        .max_by(|&&a, &&b| self.nodes[a].width.total_cmp(&self.nodes[b].width));
+                    widest != Some(&i)
+                }
+            };
+            if !is_chain_start { continue; }
+
+            let mut chain: Vec<usize> = node.parent.into_iter().collect();
+            let mut current = i;
+            loop {
+                chain.push(current);
+                let next = children[current].iter()
+                    .max_by(|&&a, &&b| self.nodes[a].width.total_cmp(&self.nodes[b].width));
+                match next {
+                    Some(&next) => current = next,
+                    None => break,
+                }
+            }
+            chains.push(chain);
+        }
+        chains
+    }
+
+    /// Branch chains in drawing order.
+    /// Roots go first, so the trunk covers the place where they join.
+    fn get_drawing_order(&self) -> Vec<Vec<usize>> {
+        let (mut roots, branches): (Vec<_>, Vec<_>) = self.get_branch_chains().into_iter()
+            .partition(|chain| self.nodes[*chain.last().unwrap()].underground);
+        roots.extend(branches);
+        roots
+    }
+
+    pub fn display(&self, renderer: &mut dyn Renderer) {
+        for chain in self.get_drawing_order() {
+            self.display_branch(&chain, renderer);
+        }
+        self.display_snow(renderer);
+        self.display_leaves(renderer);
+    }
+
+    fn display_leaves(&self, renderer: &mut dyn Renderer) {
+        let haze = get_haze(self.depth);
+        for leaf in self.leaves.iter().filter(|leaf| leaf.on_branch) {
+            let node = &self.nodes[leaf.node];
+            let color = apply_haze(get_leaf_color(self.season, leaf.variation), haze);
+            display_leaf(renderer, node.pos, node.angle + leaf.angle, leaf.size, color);
+        }
+        for leaf in &self.falling_leaves {
+            let color = apply_haze(get_leaf_color(self.season, leaf.variation), haze);
+            display_leaf(renderer, leaf.pos, leaf.angle, leaf.size, color);
+        }
+    }
+
+    /// Snow lies on top of branches, more of it on the level ones.
+    fn display_snow(&self, renderer: &mut dyn Renderer) {
+        if self.snow_cover <= 0.0 { return; }
+        let color = apply_haze(SNOW_COLOR, get_haze(self.depth));
+        for node in self.nodes.iter().filter(|node| !node.underground) {
+            let Some(parent) = node.parent else { continue };
+            let thickness = self.snow_cover * SNOW_THICKNESS * node.angle.cos().abs() * node.width.min(1.0);
+            if thickness < 0.5 { continue; }
+            let up = Vec2::NEG_Y * (node.width + thickness) / 2.0;
+            renderer.draw_line(self.nodes[parent].pos + up, node.pos + up, thickness, color);
+        }
+    }
+
+    /// Branch starts with its own width, not with the width of the node it grows from.
+    fn get_chain_width(&self, chain: &[usize], k: usize) -> f32 {
+        self.nodes[chain[k.max(1)]].width
+    }
+
+    /// Left and right edge points of the branch at every node of the chain.
+    fn get_branch_outline(&self, chain: &[usize]) -> Vec<(Vec2, Vec2)> {
+        let mut sides = Vec::with_capacity(chain.len());
+        for k in 0..chain.len() {
+            let prev = self.nodes[chain[k.saturating_sub(1)]].pos;
+            let next = self.nodes[chain[(k + 1).min(chain.len() - 1)]].pos;
+            let direction = next - prev;
+            let angle = if direction == Vec2::ZERO { self.nodes[chain[k]].angle } else { direction.y.atan2(direction.x) };
+            sides.push(get_bulked_points(self.nodes[chain[k]].pos, angle, self.get_chain_width(chain, k)));
+        }
+        sides
+    }
+
+    /// Draws one branch as a tapered triangle strip with round joints.
+    fn display_branch(&self, chain: &[usize], renderer: &mut dyn Renderer) {
+        if chain.len() < 2 { return; }
+
+        let sides = self.get_branch_outline(chain);
+        for k in 0..chain.len() - 1 {
+            let (left_a, right_a) = sides[k];
+            let (left_b, right_b) = sides[k + 1];
+            let color_a = self.get_node_color(chain[k.max(1)]);
+            let color_b = self.get_node_color(chain[k + 1]);
+            renderer.draw_quad([left_a, left_b, right_b, right_a], [color_a, color_b, color_b, color_a]);
+        }
+
+        for k in 1..chain.len() {
+            let node = &self.nodes[chain[k]];
+            renderer.draw_circle(node.pos, self.get_chain_width(chain, k) / 2.0, self.get_node_color(chain[k]));
+        }
+    }
+
+    /// Renders the tree as a standalone SVG document.
+    /// Every branch becomes one tapered path, joints are round.
+    pub fn to_svg(&self) -> String {
+        const MARGIN: f32 = 10.0;
+        let mut min = Vec2::splat(f32::INFINITY);
+        let mut max = Vec2::splat(f32::NEG_INFINITY);
+        for node in &self.nodes {
+            min = min.min(node.pos - Vec2::splat(node.width));
+            max = max.max(node.pos + Vec2::splat(node.width));
+        }
+        if self.nodes.is_empty() {
+            (min, max) = (Vec2::ZERO, Vec2::ZERO);
+        }
+        let (min, size) = (min - Vec2::splat(MARGIN), max - min + Vec2::splat(MARGIN * 2.0));
+
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"{} {} {} {}\" width=\"{}\" height=\"{}\">\n",
+            min.x, min.y, size.x, size.y, size.x.ceil(), size.y.ceil()
+        );
+        for chain in self.get_drawing_order() {
+            if chain.len() < 2 { continue; }
+            let color = svg_color(self.get_node_color(chain[1]));
+            let sides = self.get_branch_outline(&chain);
+
+            // Along the left edge, then back along the right one
+            let mut path = String::new();
+            for (k, (left, _)) in sides.iter().enumerate() {
+                path += &format!("{}{:.2},{:.2} ", if k == 0 { "M" } else { "L" }, left.x, left.y);
+            }
+            for (_, right) in sides.iter().rev() {
+                path += &format!("L{:.2},{:.2} ", right.x, right.y);
+            }
+            svg += &format!("  <path d=\"{}Z\" fill=\"{}\"/>\n", path, color);
+
+            for k in 1..chain.len() {
+                let node = &self.nodes[chain[k]];
+                svg += &format!(
+                    "  <circle cx=\"{:.2}\" cy=\"{:.2}\" r=\"{:.2}\" fill=\"{}\"/>\n",
+                    node.pos.x, node.pos.y, self.get_chain_width(&chain, k) / 2.0, svg_color(self.get_node_color(chain[k]))
+                );
+            }
+        }
+        svg += "</svg>\n";
+        svg
+    }
+
+    /// Plain description of every node, for saving and editing trees outside.
+    pub fn to_skeleton(&self) -> Vec<SkeletonNode> {
+        self.nodes.iter().map(|node| SkeletonNode {
+            pos: node.pos,
+            width: node.width,
+            angle: node.angle,
+            length: node.length,
+            level: node.level,
+            parent: node.parent,
+            underground: node.underground,
+        }).collect()
+    }
+
+    /// Builds a tree back from a skeleton. Parents must come before their children.
+    pub fn from_skeleton(skeleton: &[SkeletonNode]) -> Result<Tree, String> {
+        let mut tree = Tree::new();
+        for (i, node) in skeleton.iter().enumerate() {
+            match node.parent {
+                None if i != 0 => return Err(format!("node {i} has no parent, only node 0 can be the root")),
+                Some(parent) if parent >= i => return Err(format!("node {i} has parent {parent}, parents must come first")),
+                _ => {},
+            }
+            tree.add_node(Node {
+                width: node.width,
+                angle: node.angle,
+                length: node.length,
+                level: node.level,
+                parent: node.parent,
+                pos: node.pos,
+                particle: None,
+                underground: node.underground,
+                strahler: 0,
+                branch: 0,
+                age: 0,
+            });
+        }
+        if let Some(root) = tree.nodes.first() {
+            tree.ground_level = root.pos.y;
+        }
+        tree.compute_hierarchy();
+        tree.grow_leaves();
+        Ok(tree)
+    }
+
+    fn get_node_color(&self, node_id: usize) -> Color {
+        apply_haze(get_bark_color(node_id, self.nodes[node_id].underground), get_haze(self.depth))
+    }
+
+    pub fn root_pos(&self) -> Vec2 {
+        self.nodes[0].pos
+    }
+
+    /// Every branch segment as its start, end and width.
+    pub fn get_segments(&self) -> Vec<(Vec2, Vec2, f32)> {
+        self.nodes.iter()
+            .filter_map(|node| node.parent.map(|parent| (self.nodes[parent].pos, node.pos, node.width)))
+            .collect()
+    }
+
+    fn has_root_system(&self) -> bool {
+        self.nodes.iter().any(|node| node.underground)
+    }
+
+    /// First nodes of the root system, they anchor the tree.
+    fn is_root_system_base(&self, node_id: usize) -> bool {
+        let node = &self.nodes[node_id];
+        node.underground && node.parent.is_some_and(|parent| !self.nodes[parent].underground)
+    }
+
+    /// Grows roots downward from the base of the trunk.
+    /// Should be called before `init_simulation`.
+    pub fn grow_roots(&mut self) {
+        for k in 0..ROOT_SYSTEM_COUNT {
+            let base = &self.nodes[0];
+            // Spread root bases evenly around straight down
+            let t = k as f32 / (ROOT_SYSTEM_COUNT - 1) as f32;
+            let angle = ROOT_SYSTEM_DOWN + (t * 2.0 - 1.0) * ROOT_SYSTEM_SPREAD.to_radians();
+            let length = base.length * ROOT_SYSTEM_LENGTH_COEFFICIENT;
+
+            let root = Node {
+                width: base.width * ROOT_SYSTEM_WIDTH_COEFFICIENT,
+                angle,
+                length,
+                level: base.level + 1,
+                pos: base.pos + Vec2::from_angle(angle) * length,
+                parent: Some(0),
+                particle: None,
+                underground: true,
+                strahler: 0,
+                branch: 0,
+                age: 0,
+            };
+            let root = self.add_node(root);
+            recursive_root_gen(self, root);
+        }
+        self.compute_hierarchy();
+    }
+
+    /// Recomputes Strahler orders, ages and branch identifiers of all nodes.
+    /// Levels are set during generation, since only the generator knows where the splits are.
+    fn compute_hierarchy(&mut self) {
+        // Children always come after their parents, so going backwards visits children first
+        let children = self.get_children();
+        for i in (0..self.nodes.len()).rev() {
+            self.nodes[i].age = children[i].iter().map(|&child| self.nodes[child].age + 1).max().unwrap_or(0);
+
+            let mut order = 1;
+            let mut max_count = 0;
+            for &child in &children[i] {
+                let child_order = self.nodes[child].strahler;
+                if child_order > order {
+                    order = child_order;
+                    max_count = 1;
+                } else if child_order == order {
+                    max_count += 1;
+                }
+            }
+            // Two streams of the same highest order make a bigger one
+            self.nodes[i].strahler = if max_count > 1 { order + 1 } else { order };
+        }
+
+        let chains = self.get_branch_chains();
+        for (branch, chain) in chains.iter().enumerate() {
+            // Every chain but the trunk starts with the node it grows from, that one belongs to another branch
+            let skip = if branch == 0 { 0 } else { 1 };
+            for &node in &chain[skip..] {
+                self.nodes[node].branch = branch;
+            }
+        }
+        self.branch_count = chains.len();
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn branch_count(&self) -> usize {
+        self.branch_count
+    }
+
+    /// Branching depth, the trunk is level 0 and every split adds one.
+    pub fn level(&self, node_id: usize) -> u32 {
+        self.nodes[node_id].level
+    }
+
+    /// Strahler order, tips have order 1 and the trunk has the highest one.
+    pub fn strahler_order(&self, node_id: usize) -> u32 {
+        self.nodes[node_id].strahler
+    }
+
+    /// Segments between the node and its furthest tip, older wood is closer to the base.
+    pub fn age(&self, node_id: usize) -> u32 {
+        self.nodes[node_id].age
+    }
+
+    /// Identifier of the branch the node is part of, the trunk is branch 0.
+    pub fn branch_id(&self, node_id: usize) -> usize {
+        self.nodes[node_id].branch
+    }
+
+    pub fn parent_of(&self, node_id: usize) -> Option<usize> {
+        self.nodes[node_id].parent
+    }
+
+    pub fn children_of(&self, node_id: usize) -> Vec<usize> {
+        // Children always come after their parents
+        (node_id + 1..self.nodes.len()).filter(|&i| self.nodes[i].parent == Some(node_id)).collect()
+    }
+
+    /// Nodes from the given one down to the root, both included.
+    pub fn path_to_root(&self, node_id: usize) -> Vec<usize> {
+        let mut path = vec![node_id];
+        let mut current = node_id;
+        while let Some(parent) = self.nodes[current].parent {
+            path.push(parent);
+            current = parent;
+        }
+        path
+    }
+
+    /// The node with all of its descendants, in tree order.
+    pub fn get_subtree(&self, node_id: usize) -> Vec<usize> {
+        let mut in_subtree = vec![false; self.nodes.len()];
+        in_subtree[node_id] = true;
+        for i in node_id + 1..self.nodes.len() {
+            if let Some(parent) = self.nodes[i].parent {
+                in_subtree[i] = in_subtree[parent];
+            }
+        }
+        (node_id..self.nodes.len()).filter(|&i| in_subtree[i]).collect()
+    }
+
+    /// Copies the subtree into a new tree with the given node as its root.
+    /// Nodes keep their particles, so the copy still follows the simulation.
+    pub fn extract_subtree(&self, node_id: usize) -> Tree {
+        let subtree = self.get_subtree(node_id);
+        let mut new_ids = vec![None; self.nodes.len()];
+        let mut tree = Tree::new();
+        tree.species = self.species.clone();
+        tree.depth = self.depth;
+        tree.ground_level = self.ground_level;
+        tree.snow = self.snow;
+        tree.season = self.season;
+        tree.time = self.time;
+        for &i in &subtree {
+            let node = &self.nodes[i];
+            let parent = if i == node_id { None } else { node.parent.and_then(|parent| new_ids[parent]) };
+            new_ids[i] = Some(tree.add_node(Node { parent, ..*node }));
+        }
+        tree.leaves = self.leaves.iter()
+            .filter_map(|leaf| new_ids[leaf.node].map(|node| Leaf { node, ..*leaf }))
+            .collect();
+        tree.compute_hierarchy();
+        tree
+    }
+
+    /// Takes the subtree out of this tree and returns it as a tree of its own.
+    fn split_off(&mut self, node_id: usize) -> Tree {
+        let piece = self.extract_subtree(node_id);
+
+        let mut removed = vec![false; self.nodes.len()];
+        for i in self.get_subtree(node_id) {
+            removed[i] = true;
+        }
+        let mut new_ids = vec![None; self.nodes.len()];
+        let mut nodes = Vec::with_capacity(self.nodes.len());
+        for (i, node) in self.nodes.drain(..).enumerate() {
+            if removed[i] { continue; }
+            new_ids[i] = Some(nodes.len());
+            let parent = node.parent.and_then(|parent| new_ids[parent]);
+            nodes.push(Node { parent, ..node });
+        }
+        self.nodes = nodes;
+        self.leaves = self.leaves.drain(..)
+            .filter_map(|leaf| new_ids[leaf.node].map(|node| Leaf { node, ..leaf }))
+            .collect();
+        self.compute_hierarchy();
+        piece
+    }
+
+    /// Cuts every branch segment crossed by the line and returns the pieces that fell off.
+    /// Pieces keep their particles and inner constraints, only the links to this tree are removed.
+    pub fn cut(&mut self, from: Vec2, to: Vec2, simulation: &mut ParticleSimulation) -> Vec<Tree> {
+        let mut pieces = Vec::new();
+        // Static trees can't fall apart
+        if !self.is_simulated() { return pieces; }
+        loop {
+            let crossed = (0..self.nodes.len()).find(|&i| {
+                let node = &self.nodes[i];
+                !node.underground && node.parent.is_some_and(|parent| segments_intersect(from, to, self.nodes[parent].pos, node.pos))
+            });
+            let Some(node_id) = crossed else { break };
+
+            let particles: Vec<usize> = self.get_subtree(node_id).iter().filter_map(|&i| self.nodes[i].particle).collect();
+            simulation.detach(&particles);
+            pieces.push(self.split_off(node_id));
+        }
+        pieces
+    }
+
+    /// Accelerates every particle of the tree, for gravity on fallen pieces.
+    pub fn accelerate(&self, simulation: &mut ParticleSimulation, acc: Vec2) {
+        for particle in self.nodes.iter().filter_map(|node| node.particle) {
+            simulation.particles[particle].accelerate(acc);
+        }
+    }
+
+    /// Stops particles of the tree from falling through the ground.
+    pub fn keep_above_ground(&self, simulation: &mut ParticleSimulation) {
+        for node in &self.nodes {
+            if let Some(particle) = node.particle {
+                let particle = &mut simulation.particles[particle];
+                particle.pos.y = particle.pos.y.min(self.ground_level - node.width / 2.0);
+            }
+        }
+    }
+
+    /// Puts leaves on the thin twigs.
+    fn grow_leaves(&mut self) {
+        for (i, node) in self.nodes.iter().enumerate() {
+            if node.underground || node.width > LEAF_TWIG_WIDTH { continue; }
+            for _ in 0..LEAVES_PER_NODE {
+                self.leaves.push(Leaf {
+                    node: i,
+                    angle: sign() * uniform(LEAF_MIN_ANGLE, LEAF_MAX_ANGLE).to_radians(),
+                    size: node.length * LEAF_SIZE * uniform(0.7, 1.3),
+                    seed: unit(),
+                    variation: uniform(-LEAF_SEASON_VARIATION, LEAF_SEASON_VARIATION),
+                    on_branch: true,
+                });
+            }
+        }
+        let season = self.season;
+        self.set_leaves_for_season(season);
+    }
+
+    fn set_leaves_for_season(&mut self, season: f32) {
+        let density = get_leaf_density(season);
+        for leaf in &mut self.leaves {
+            leaf.on_branch = leaf.seed < density;
+        }
+        self.season = season;
+    }
+
+    /// Moves the tree through the year, `season` goes from 0 to 1.
+    /// Leaves grow, change color and fall, snow piles up on branches in winter.
+    /// Falling leaves only happen on simulated trees, static ones just lose them.
+    pub fn update_season(&mut self, season: f32, dt: f32, simulation: &mut ParticleSimulation) {
+        self.time += dt;
+        let density = get_leaf_density(season);
+        let simulated = self.is_simulated();
+
+        let mut fallen = Vec::new();
+        for (i, leaf) in self.leaves.iter_mut().enumerate() {
+            let on_branch = leaf.seed < density;
+            if leaf.on_branch && !on_branch && simulated && is_leaf_fall(season) {
+                fallen.push(i);
+            }
+            leaf.on_branch = on_branch;
+        }
+        self.season = season;
+
+        for i in fallen {
+            self.drop_leaf(i, simulation);
+        }
+        self.update_falling_leaves(dt, simulation);
+        self.update_snow(simulation);
+    }
+
+    fn drop_leaf(&mut self, leaf_id: usize, simulation: &mut ParticleSimulation) {
+        let leaf = &self.leaves[leaf_id];
+        let node = &self.nodes[leaf.node];
+        let angle = node.angle + leaf.angle;
+        let pos = node.pos + Vec2::from_angle(angle) * leaf.size / 2.0;
+
+        let particle = match self.spare_leaf_particles.pop() {
+            Some(particle) => {
+                simulation.teleport(particle, pos);
+                simulation.set_active(particle, true);
+                simulation.set_fixed(particle, false);
+                particle
+            },
+            None => simulation.new_particle(pos, leaf.size / 4.0, LEAF_MASS, false),
+        };
+        simulation.set_damping(particle, LEAF_DRAG);
+
+        self.falling_leaves.push(FallingLeaf {
+            particle,
+            pos,
+            angle,
+            size: leaf.size,
+            variation: leaf.variation,
+            phase: uniform(0.0, PI * 2.0),
+            landed_at: None,
+        });
+    }
+
+    fn update_falling_leaves(&mut self, dt: f32, simulation: &mut ParticleSimulation) {
+        for leaf in &mut self.falling_leaves {
+            if leaf.landed_at.is_some() { continue; }
+            leaf.pos = simulation.particles[leaf.particle].pos;
+            if leaf.pos.y >= self.ground_level {
+                leaf.pos.y = self.ground_level;
+                simulation.teleport(leaf.particle, leaf.pos);
+                simulation.set_fixed(leaf.particle, true);
+                leaf.landed_at = Some(self.time);
+                continue;
+            }
+            // Swinging from side to side on the way down
+            let flutter = (self.time * LEAF_FLUTTER_FREQUENCY + leaf.phase).sin();
+            simulation.particles[leaf.particle].accelerate(Vec2::new(flutter * LEAF_FLUTTER_STRENGTH, LEAF_GRAVITY));
+            leaf.angle += flutter * LEAF_SPIN * dt;
+        }
+
+        // Leaves rot away some time after landing, their particles get reused
+        let (time, spare) = (self.time, &mut self.spare_leaf_particles);
+        self.falling_leaves.retain(|leaf| {
+            let rotten = leaf.landed_at.is_some_and(|landed_at| time - landed_at > LEAF_ROT_TIME);
+            if rotten {
+                simulation.set_active(leaf.particle, false);
+                spare.push(leaf.particle);
+            }
+            !rotten
+        });
+    }
+
+    /// Snow makes branches heavier and pulls them down.
+    fn update_snow(&mut self, simulation: &mut ParticleSimulation) {
+        let cover = if self.snow { get_snow_cover(self.season) } else { 0.0 };
+        if cover == 0.0 && self.snow_cover == 0.0 { return; }
+        self.snow_cover = cover;
+        if !self.is_simulated() { return; }
+
         for i in 0..self.nodes.len() {
             let node = &self.nodes[i];
+            let Some(particle) = node.particle else { continue };
+            if node.underground { continue; }
+            let mass = self.get_node_physics(i).mass;
+            let snow_mass = cover * SNOW_DENSITY * node.length * node.angle.cos().abs();
+            simulation.set_mass(particle, mass + snow_mass);
+            simulation.particles[particle].accelerate(Vec2::new(0.0, SNOW_GRAVITY * snow_mass / (mass + snow_mass)));
+        }
+    }
 
-            if let Some(parent) = node.parent {
-                let parent = &self.nodes[parent];
-                const LINE_COLOR: Color = Color::from_rgb(237.0/255.0, 198.0/255.0, 114.0/255.0);
-                graphics.draw_line::<(f32, f32), (f32, f32)>(parent.pos.into(), node.pos.into(), node.width, LINE_COLOR);
+    /// Number of nodes on every branching level.
+    pub fn count_per_level(&self) -> Vec<usize> {
+        let mut counts = Vec::new();
+        for node in &self.nodes {
+            let level = node.level as usize;
+            if counts.len() <= level {
+                counts.resize(level + 1, 0);
             }
+            counts[level] += 1;
         }
+        counts
+    }
+
+    fn get_node_physics(&self, node_id: usize) -> NodePhysics {
+        let node = &self.nodes[node_id];
+        let (max_width, max_age) = (self.nodes[0].width, self.nodes[0].age);
+        self.species.get_node_physics(node.width, node.level, node.age, max_width, max_age)
     }
 
-    pub fn init_simulation(&self, simulation: &mut ParticleSimulation) {
+    /// Adds a particle for every node and links them with constraints.
+    /// Stiffness, bending and damping of every segment come from the tree species.
+    /// Nodes remember their particles, so the simulation may already hold other particles.
+    pub fn init_simulation(&mut self, simulation: &mut ParticleSimulation) {
         for i in 0..self.nodes.len() {
             let node = &self.nodes[i];
             //let (a, b) = node.get_bulked_points();
             //simulation.new_particle(a, 1.0,  1.0, i == 0);
-            simulation.new_particle(node.pos, node.width, node.width*node.width, i == 0);
+            // Roots anchor the tree if there are any, otherwise the trunk base is fixed
+            let fixed = if self.has_root_system() { self.is_root_system_base(i) } else { i == 0 };
+            let physics = self.get_node_physics(i);
+            let particle = simulation.new_particle(node.pos, node.width, physics.mass, fixed);
+            simulation.set_damping(particle, physics.damping);
+            self.nodes[i].particle = Some(particle);
 
+            // Parents are always added before their children
+            let node = &self.nodes[i];
             if let Some(parent) = node.parent {
                 let parent_node = &self.nodes[parent];
-                simulation.new_distance_constrain_in_place(i, parent);
+                let parent_particle = parent_node.particle.unwrap();
+                simulation.new_soft_distance_constrain_in_place(particle, parent_particle, physics.stiffness);
                 if let Some(grandparent) = parent_node.parent {
-                    simulation.new_angle_constrain_in_place(i, parent, grandparent);
+                    let grandparent_particle = self.nodes[grandparent].particle.unwrap();
+                    simulation.new_soft_angle_constrain_in_place(particle, parent_particle, grandparent_particle, physics.bend_stiffness);
                 }
                 //simulation.new_distance_constrain_in_place(i*2, parent*2);
                 //simulation.new_distance_constrain_in_place(i*2+1, parent*2);
@@ -91,52 +868,128 @@ impl Tree {
             }
         }
     }
+
+    pub fn is_simulated(&self) -> bool {
+        self.nodes.iter().any(|node| node.particle.is_some())
+    }
+
+    /// Reads node positions back from the simulation and derives node angles from them.
+    pub fn sync_from_simulation(&mut self, simulation: &ParticleSimulation) {
+        // Static trees have nothing to read back
+        if !self.is_simulated() { return; }
+
+        for node in &mut self.nodes {
+            if let Some(particle) = node.particle {
+                node.pos = simulation.particles[particle].pos;
+            }
+        }
+
+        for i in 0..self.nodes.len() {
+            if let Some(parent) = self.nodes[i].parent {
+                let direction = self.nodes[i].pos - self.nodes[parent].pos;
+                if direction != Vec2::ZERO {
+                    self.nodes[i].angle = direction.y.atan2(direction.x);
+                }
+            }
+        }
+    }
 }
 
-const ROOT_WIDTH: f32 = 10.0;
 const ROOT_ANGLE: f32 = -PI/2.0; // Up
-const ROOT_LENGTH: f32 = 40.0;
 
 const ANGLE_DEVIATION: f32 = 10.0; // Degrees
                                    //
 const SPLIT_ANGLE_DEVIATION: f32 = 10.0;
-const SPLIT_ANGLE_DEFAULT: f32 = 30.0;
 
 
-const WIDTH_COEFFICIENT: f32 = 0.90;
 const SPLIT_WIDTH_COEFFICIENT: f32 = 0.75;
 const LENGTH_COEFFICIENT: f32 = 0.99;
 
 const WIDTH_THRESHOLD: f32 = 1.0;
 
+const AVOIDANCE_RANGE: f32 = 1.5; // In segment lengths
+const AVOIDANCE_STRENGTH: f32 = 1.0;
+
+const BARK_HUE: f32 = 0.07;
+const BARK_SATURATION: f32 = 0.55;
+const BARK_VALUE: f32 = 0.30;
+const BARK_VALUE_VARIATION: f32 = 0.15;
+
+pub const GROUND_LEVEL: f32 = 700.0;
+const SOIL_SATURATION: f32 = 0.6;
+const SOIL_VALUE: f32 = 0.15;
+const GRASS_HUE: f32 = 0.28;
+const GRASS_VALUE: f32 = 0.45;
+
+const LEAF_TWIG_WIDTH: f32 = 2.5;
+const LEAVES_PER_NODE: usize = 2;
+const LEAF_MIN_ANGLE: f32 = 25.0; // Degrees from the twig
+const LEAF_MAX_ANGLE: f32 = 70.0;
+const LEAF_SIZE: f32 = 0.4; // In segment lengths
+const LEAF_WIDTH: f32 = 0.3; // Relative to leaf length
+const LEAF_SEASON_VARIATION: f32 = 0.04;
+const LEAF_MASS: f32 = 0.05;
+const LEAF_DRAG: f32 = 0.08;
+const LEAF_GRAVITY: f32 = 300.0;
+const LEAF_FLUTTER_FREQUENCY: f32 = 3.0;
+const LEAF_FLUTTER_STRENGTH: f32 = 600.0;
+const LEAF_SPIN: f32 = 4.0;
+const LEAF_ROT_TIME: f32 = 20.0; // Seconds on the ground
+
+const SNOW_COLOR: Color = Color::from_rgb(0.95, 0.97, 1.0);
+const SNOW_THICKNESS: f32 = 4.0;
+const SNOW_DENSITY: f32 = 0.2;
+const SNOW_GRAVITY: f32 = 400.0;
+
+const HAZE_COLOR: Color = Color::from_rgb(0.45, 0.5, 0.6);
+const ATMOSPHERE_DENSITY: f32 = 1.2;
+
+const ROOT_SYSTEM_COUNT: usize = 4;
+const ROOT_SYSTEM_DOWN: f32 = PI/2.0;
+const ROOT_SYSTEM_SPREAD: f32 = 70.0; // Degrees from straight down
+const ROOT_SYSTEM_MIN_ANGLE: f32 = 5.0; // Degrees from the ground line
+const ROOT_SYSTEM_GRAVITROPISM: f32 = 0.08;
+const ROOT_SYSTEM_SPLIT_CHANCE: f32 = 0.25;
+const ROOT_SYSTEM_WIDTH_COEFFICIENT: f32 = 0.6;
+const ROOT_SYSTEM_WIDTH_DECAY: f32 = 0.8; // Roots thin out faster than branches, so they stay shallow
+const ROOT_SYSTEM_LENGTH_COEFFICIENT: f32 = 0.5;
+const ROOT_SYSTEM_WIDTH_THRESHOLD: f32 = 1.5;
+const ROOT_SYSTEM_SATURATION: f32 = 0.35;
+const ROOT_SYSTEM_VALUE: f32 = 0.45;
+
 fn get_angle_deviation() -> f32 {
-    thread_rng().gen_range(-ANGLE_DEVIATION..ANGLE_DEVIATION).to_radians()
+    uniform(-ANGLE_DEVIATION, ANGLE_DEVIATION).to_radians()
 }
 
-fn get_split_angle() -> f32 {
-    ((SPLIT_ANGLE_DEFAULT + thread_rng().gen_range(-SPLIT_ANGLE_DEVIATION..SPLIT_ANGLE_DEVIATION)) * if thread_rng().gen_bool(0.5) { 1.0 } else { -1.0 }).to_radians()
+fn get_split_angle(split_angle: f32) -> f32 {
+    ((split_angle + uniform(-SPLIT_ANGLE_DEVIATION, SPLIT_ANGLE_DEVIATION)) * sign()).to_radians()
 }
 
-fn move_forward(tree: &mut Tree, ref_root: usize) -> usize {
+fn move_forward(tree: &mut Tree, ref_root: usize, environment: &Environment) -> Option<usize> {
     let ancestor = &tree.nodes[ref_root];
-    let new_node = ancestor.derive_from(ref_root);
-    tree.add_node(new_node)
+    let mut new_node = ancestor.derive_from(ref_root, &tree.species);
+    if !new_node.steer(ancestor.pos, environment, &tree.species) { return None; }
+    Some(tree.add_node(new_node))
 }
 
-fn make_split(tree: &mut Tree, ref_root: usize) -> usize {
+fn make_split(tree: &mut Tree, ref_root: usize, environment: &Environment) -> Option<usize> {
     let ancestor = &tree.nodes[ref_root];
-    let mut new_node = ancestor.derive_from(ref_root);
-    new_node.angle += get_split_angle();
+    let mut new_node = ancestor.derive_from(ref_root, &tree.species);
+    new_node.angle += get_split_angle(tree.species.split_angle);
     new_node.width *= SPLIT_WIDTH_COEFFICIENT;
-    tree.add_node(new_node)
+    new_node.level += 1;
+    if !new_node.steer(ancestor.pos, environment, &tree.species) { return None; }
+    Some(tree.add_node(new_node))
 }
 
-fn recursive_gen(tree: &mut Tree, mut previous: usize) {
-    loop {
-        previous = move_forward(tree, previous);
+fn recursive_gen(tree: &mut Tree, mut previous: usize, environment: &Environment) {
+    // Branch stops where there is no room left
+    while let Some(next) = move_forward(tree, previous, environment) {
+        previous = next;
         if previous % 3 == 0 && previous != 0 {
-            let split = make_split(tree, previous);
-            recursive_gen(tree, split);
+            if let Some(split) = make_split(tree, previous, environment) {
+                recursive_gen(tree, split, environment);
+            }
         }
         if tree.nodes[previous].width < WIDTH_THRESHOLD {
             break;
@@ -144,23 +997,153 @@ fn recursive_gen(tree: &mut Tree, mut previous: usize) {
     }
 }
 
+fn recursive_root_gen(tree: &mut Tree, mut previous: usize) {
+    loop {
+        let new_node = tree.nodes[previous].derive_root_from(previous, 0.0);
+        previous = tree.add_node(new_node);
+        if chance(ROOT_SYSTEM_SPLIT_CHANCE) {
+            let mut split = tree.nodes[previous].derive_root_from(previous, get_split_angle(tree.species.split_angle));
+            split.width *= SPLIT_WIDTH_COEFFICIENT;
+            split.level += 1;
+            let split = tree.add_node(split);
+            recursive_root_gen(tree, split);
+        }
+        if tree.nodes[previous].width < ROOT_SYSTEM_WIDTH_THRESHOLD {
+            break;
+        }
+    }
+}
+
 pub fn generate_tree() -> Tree {
+    generate_tree_at(Vec2::new(WORLD_SIZE.x / 2.0, GROUND_LEVEL), Species::default())
+}
+
+pub fn generate_tree_at(pos: Vec2, species: Species) -> Tree {
+    generate_tree_in(pos, species, &Environment::default())
+}
+
+/// Grows a tree that bends towards the light and around whatever is in the environment.
+pub fn generate_tree_in(pos: Vec2, species: Species, environment: &Environment) -> Tree {
     let mut tree = Tree::new();
     
     // Generate root
     let root = Node {
-        width: ROOT_WIDTH,
+        width: species.trunk_width,
         angle: ROOT_ANGLE,
-        length: ROOT_LENGTH,
+        length: species.segment_length,
         level: 0,
-        pos: Vec2::new(1280.0 / 2.0, 700.0),
+        pos,
         parent: None,
+        particle: None,
+        underground: false,
+        strahler: 0,
+        branch: 0,
+        age: 0,
     };
 
+    tree.species = species;
+    tree.ground_level = pos.y;
     let ref_root = tree.add_node(root);
 
     // Grow tree
-    recursive_gen(&mut tree, ref_root);
+    recursive_gen(&mut tree, ref_root, environment);
+    tree.compute_hierarchy();
+    tree.grow_leaves();
 
     tree
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Trunk 0 -> 1 -> 2 -> 4 going up, a side branch 1 -> 3 -> 6 to the right and a twig 2 -> 5 to the left.
+    /// Only the thin 5 and 6 get leaves.
+    fn small_tree() -> Tree {
+        let node = |x: f32, y: f32, parent: Option<usize>, width: f32, level: u32| {
+            SkeletonNode { pos: Vec2::new(x, y), width, angle: -PI / 2.0, length: 10.0, level, parent, underground: false }
+        };
+        let skeleton = [
+            node(0.0, 0.0, None, 10.0, 0),
+            node(0.0, -10.0, Some(0), 8.0, 0),
+            node(0.0, -20.0, Some(1), 6.0, 0),
+            node(10.0, -20.0, Some(1), 3.0, 1),
+            node(0.0, -30.0, Some(2), 4.0, 0),
+            node(-10.0, -30.0, Some(2), 2.0, 1),
+            node(20.0, -30.0, Some(3), 2.0, 1),
+        ];
+        Tree::from_skeleton(&skeleton).unwrap()
+    }
+
+    #[test]
+    fn strahler_orders_and_ages() {
+        let tree = small_tree();
+        let orders: Vec<u32> = (0..tree.node_count()).map(|i| tree.strahler_order(i)).collect();
+        assert_eq!(orders, [2, 2, 2, 1, 1, 1, 1]);
+        let ages: Vec<u32> = (0..tree.node_count()).map(|i| tree.age(i)).collect();
+        assert_eq!(ages, [3, 2, 1, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn branches_follow_the_widest_child() {
+        let tree = small_tree();
+        let branches: Vec<usize> = (0..tree.node_count()).map(|i| tree.branch_id(i)).collect();
+        assert_eq!(branches, [0, 0, 0, 1, 0, 2, 1]);
+        assert_eq!(tree.branch_count(), 3);
+    }
+
+    #[test]
+    fn walking_the_tree() {
+        let tree = small_tree();
+        assert_eq!(tree.children_of(1), [2, 3]);
+        assert!(tree.children_of(6).is_empty());
+        assert_eq!(tree.path_to_root(6), [6, 3, 1, 0]);
+        assert_eq!(tree.path_to_root(0), [0]);
+        assert_eq!(tree.get_subtree(3), [3, 6]);
+        assert_eq!(tree.get_subtree(1), [1, 2, 3, 4, 5, 6]);
+        assert_eq!(tree.count_per_level(), [4, 3]);
+    }
+
+    #[test]
+    fn cutting_splits_off_the_subtree() {
+        let mut tree = small_tree();
+        let mut simulation = ParticleSimulation::new();
+        tree.init_simulation(&mut simulation);
+        let particles: Vec<usize> = tree.nodes.iter().map(|node| node.particle.unwrap()).collect();
+        let leaves_on = |tree: &Tree, node: usize| tree.leaves.iter().filter(|leaf| leaf.node == node).count();
+        let (leaves_on_5, leaves_on_6) = (leaves_on(&tree, 5), leaves_on(&tree, 6));
+        assert!(leaves_on_5 > 0 && leaves_on_6 > 0);
+        let (distance_before, angle_before) = simulation.get_constrained_particles();
+
+        // Crosses only the segment from 1 to 3
+        let pieces = tree.cut(Vec2::new(5.0, -10.0), Vec2::new(5.0, -20.0), &mut simulation);
+        assert_eq!(pieces.len(), 1);
+
+        // Piece is 3 with 6 hanging off it, and takes the leaves of 6 along
+        let piece = &pieces[0];
+        let piece_particles: Vec<usize> = piece.nodes.iter().map(|node| node.particle.unwrap()).collect();
+        assert_eq!(piece_particles, [particles[3], particles[6]]);
+        assert_eq!(piece.nodes.iter().map(|node| node.parent).collect::<Vec<_>>(), [None, Some(0)]);
+        assert_eq!(leaves_on(piece, 1), leaves_on_6);
+        assert_eq!(piece.leaves.len(), leaves_on_6);
+
+        // What's left is 0, 1, 2, 4, 5 renumbered in order
+        let left_particles: Vec<usize> = tree.nodes.iter().map(|node| node.particle.unwrap()).collect();
+        assert_eq!(left_particles, [particles[0], particles[1], particles[2], particles[4], particles[5]]);
+        assert_eq!(tree.nodes.iter().map(|node| node.parent).collect::<Vec<_>>(), [None, Some(0), Some(1), Some(2), Some(2)]);
+        assert_eq!(leaves_on(&tree, 4), leaves_on_5);
+        assert_eq!(tree.leaves.len(), leaves_on_5);
+
+        // Links from 3 to 1 and 0 are gone, everything else stays
+        let crosses = |ids: &[usize]| {
+            let cut_off = ids.iter().filter(|id| piece_particles.contains(id)).count();
+            cut_off != 0 && cut_off != ids.len()
+        };
+        let (distance_after, angle_after) = simulation.get_constrained_particles();
+        let distance_kept: Vec<_> = distance_before.into_iter().filter(|ids| !crosses(ids)).collect();
+        let angle_kept: Vec<_> = angle_before.into_iter().filter(|ids| !crosses(ids)).collect();
+        assert_eq!(distance_after, distance_kept);
+        assert_eq!(angle_after, angle_kept);
+        assert_eq!((distance_after.len(), angle_after.len()), (5, 3));
+    }
+}
@@ -0,0 +1,121 @@
+use glam::Vec2;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+use crate::tree_gen::*;
+
+/*
+ * Skeleton file looks like this, parents always come before their children:
+ * {
+ *   "nodes": [
+ *     { "pos": [640, 700], "width": 10, "angle": -1.5708, "length": 40, "level": 0, "parent": null, "underground": false },
+ *     ...
+ *   ]
+ * }
+ * "underground" can be left out, everything else is required.
+ */
+
+#[derive(Serialize, Deserialize)]
+struct SkeletonFile {
+    nodes: Vec<SkeletonNode>,
+}
+
+fn is_finite(node: &SkeletonNode) -> bool {
+    node.pos.is_finite() && node.width.is_finite() && node.angle.is_finite() && node.length.is_finite()
+}
+
+impl Tree {
+    /// Fails if any value is NaN or infinite, JSON can't hold those.
+    pub fn to_skeleton_json(&self) -> Result<String, String> {
+        let nodes = self.to_skeleton();
+        if let Some(i) = nodes.iter().position(|node| !is_finite(node)) {
+            return Err(format!("node {i} has a value that isn't a finite number"));
+        }
+        serde_json::to_string_pretty(&SkeletonFile { nodes }).map_err(|e| e.to_string())
+    }
+
+    pub fn from_skeleton_json(json: &str) -> Result<Tree, String> {
+        let file: SkeletonFile = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        Tree::from_skeleton(&file.nodes)
+    }
+
+    pub fn save_skeleton<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        fs::write(path, self.to_skeleton_json()?).map_err(|e| e.to_string())
+    }
+
+    /// Loads a skeleton and moves it so its root stands at `root`.
+    pub fn load_skeleton_at<P: AsRef<Path>>(path: P, root: Vec2) -> Result<Tree, String> {
+        let json = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut file: SkeletonFile = serde_json::from_str(&json).map_err(|e| e.to_string())?;
+        if let Some(first) = file.nodes.first() {
+            let offset = root - first.pos;
+            for node in &mut file.nodes {
+                node.pos += offset;
+            }
+        }
+        Tree::from_skeleton(&file.nodes)
+    }
+
+    pub fn save_svg<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        fs::write(path, self.to_svg()).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skeleton_survives_save_and_load() {
+        let tree = generate_tree();
+        let path = std::env::temp_dir().join(format!("skeleton_{}.json", std::process::id()));
+        tree.save_skeleton(&path).unwrap();
+        let root = tree.root_pos();
+        let loaded = Tree::load_skeleton_at(&path, root).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let (before, after) = (tree.to_skeleton(), loaded.to_skeleton());
+        assert_eq!(before.len(), after.len());
+        for (a, b) in before.iter().zip(&after) {
+            assert!(a.pos.distance(b.pos) < 1e-3);
+            assert_eq!((a.width, a.angle, a.length), (b.width, b.angle, b.length));
+            assert_eq!((a.level, a.parent, a.underground), (b.level, b.parent, b.underground));
+        }
+    }
+
+    #[test]
+    fn loading_moves_root() {
+        let json = generate_tree().to_skeleton_json().unwrap();
+        let path = std::env::temp_dir().join(format!("skeleton_moved_{}.json", std::process::id()));
+        fs::write(&path, json).unwrap();
+        let loaded = Tree::load_skeleton_at(&path, Vec2::new(10.0, 20.0)).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.root_pos(), Vec2::new(10.0, 20.0));
+    }
+
+    #[test]
+    fn missing_fields_are_errors() {
+        let json = r#"{ "nodes": [ { "pos": [0, 0], "width": 1, "angle": 0, "length": 1, "parent": null } ] }"#;
+        assert!(Tree::from_skeleton_json(json).is_err());
+        let with_level = json.replace("\"parent\"", "\"level\": 0, \"parent\"");
+        assert!(Tree::from_skeleton_json(&with_level).is_ok());
+    }
+
+    #[test]
+    fn parents_have_to_come_first() {
+        let node = |parent: &str| format!(r#"{{ "pos": [0, 0], "width": 1, "angle": 0, "length": 1, "level": 0, "parent": {parent} }}"#);
+        let file = |nodes: &[String]| format!(r#"{{ "nodes": [{}] }}"#, nodes.join(", "));
+        assert!(Tree::from_skeleton_json(&file(&[node("null"), node("0"), node("1")])).is_ok());
+        assert!(Tree::from_skeleton_json(&file(&[node("null"), node("2"), node("0")])).is_err());
+        assert!(Tree::from_skeleton_json(&file(&[node("null"), node("1")])).is_err());
+        assert!(Tree::from_skeleton_json(&file(&[node("null"), node("null")])).is_err());
+    }
+
+    #[test]
+    fn non_finite_values_are_not_written() {
+        let skeleton = [SkeletonNode { pos: Vec2::ZERO, width: f32::NAN, angle: 0.0, length: 1.0, level: 0, parent: None, underground: false }];
+        let tree = Tree::from_skeleton(&skeleton).unwrap();
+        assert!(tree.to_skeleton_json().is_err());
+    }
+}
@@ -1,9 +1,15 @@
 use rand::random;
-use speedy2d::Graphics2D;
 use speedy2d::color::Color;
 use glam::f32::Vec2;
 
+use crate::renderer::*;
+use crate::camera::*;
+
+/// Default physics timestep, the window steps once per frame at about 60 fps
 const DELTA_TIME: f32 = 1.0 / 60.0;
+const SOLVER_ITERATIONS: usize = 100;
+/// Wind slower than this, in pixels per second, drags particles less
+const CALM_WIND_SPEED: f32 = 5.0;
 
 pub struct PhysicsParticle {
     pub pos: Vec2,
@@ -14,19 +20,22 @@ pub struct PhysicsParticle {
     color: Color,
     radius: f32,
     fixed: bool,
+    damping: f32,
+    active: bool,
 }
 
 impl PhysicsParticle {
-    pub fn display(&self, graphics: &mut Graphics2D) {
-        graphics.draw_circle::<(f32, f32)>(self.pos.into(), self.radius, self.color);
+    pub fn display(&self, renderer: &mut dyn Renderer) {
+        renderer.draw_circle(self.pos, self.radius, self.color);
 
     }
     
-    pub fn physics_step(&mut self) {
-        if self.fixed { return; }
-        let velocity = self.pos - self.last_pos;
+    pub fn physics_step(&mut self, dt: f32) {
+        if self.fixed || !self.active { return; }
+        // Damping is per default step, so a second loses the same speed at any timestep
+        let velocity = (self.pos - self.last_pos) * (1.0 - self.damping).powf(dt / DELTA_TIME);
         self.last_pos = self.pos;
-        self.pos += velocity + self.acc * DELTA_TIME.powi(2);
+        self.pos += velocity + self.acc * dt.powi(2);
         self.acc = Vec2::ZERO;
     }
 
@@ -52,31 +61,129 @@ impl PhysicsParticle {
 
     pub fn solve_collision(&mut self, other: &mut PhysicsParticle) {
     }
+
+    pub fn constrain_collider(&mut self, collider: &Collider) {
+        if self.fixed || !self.active { return; }
+        let (distance, normal) = collider.get_distance(self.pos);
+        if distance < self.radius {
+            self.pos += normal * (self.radius - distance);
+        }
+    }
+}
+
+/// Static shape particles can't get into.
+#[derive(Clone, Copy)]
+pub enum Collider {
+    Circle { center: Vec2, radius: f32 },
+    Rect { min: Vec2, max: Vec2 },
+}
+
+impl Collider {
+    /// Signed distance from the point to the surface, negative inside,
+    /// and the direction that leads out of the collider.
+    pub fn get_distance(&self, point: Vec2) -> (f32, Vec2) {
+        match *self {
+            Collider::Circle { center, radius } => {
+                let diff = point - center;
+                let normal = if diff == Vec2::ZERO { Vec2::NEG_Y } else { diff.normalize() };
+                (diff.length() - radius, normal)
+            },
+            Collider::Rect { min, max } => {
+                let closest = point.clamp(min, max);
+                if closest != point {
+                    let diff = point - closest;
+                    return (diff.length(), diff.normalize());
+                }
+                // Inside, leave through the nearest side
+                let sides = [
+                    (point.x - min.x, Vec2::NEG_X),
+                    (max.x - point.x, Vec2::X),
+                    (point.y - min.y, Vec2::NEG_Y),
+                    (max.y - point.y, Vec2::Y),
+                ];
+                let (depth, normal) = sides.into_iter().min_by(|a, b| a.0.total_cmp(&b.0)).unwrap();
+                (-depth, normal)
+            },
+        }
+    }
+
+    pub fn display(&self, renderer: &mut dyn Renderer) {
+        const COLLIDER_COLOR: Color = Color::from_rgb(0.3, 0.3, 0.35);
+        match *self {
+            Collider::Circle { center, radius } => {
+                renderer.draw_circle(center, radius, COLLIDER_COLOR);
+            },
+            Collider::Rect { min, max } => {
+                renderer.draw_rectangle(min, max, COLLIDER_COLOR);
+            },
+        }
+    }
 }
 
+/// Pushes everything inside it around, acceleration in pixels per second squared.
+#[derive(Clone, Copy)]
+pub enum ForceField {
+    /// Same everywhere, gravity or steady wind
+    Uniform(Vec2),
+    /// Pulls towards the center, pushes away with negative strength, weaker further out
+    Radial { center: Vec2, radius: f32, strength: f32 },
+    /// Swirls around the center, counterclockwise with negative strength
+    Vortex { center: Vec2, radius: f32, strength: f32 },
+}
+
+impl ForceField {
+    pub fn get_acceleration(&self, point: Vec2) -> Vec2 {
+        match *self {
+            ForceField::Uniform(acc) => acc,
+            ForceField::Radial { center, radius, strength } | ForceField::Vortex { center, radius, strength } => {
+                let diff = center - point;
+                let falloff = 1.0 - diff.length() / radius;
+                if falloff <= 0.0 { return Vec2::ZERO; }
+                let direction = diff.normalize_or_zero();
+                let direction = if matches!(self, ForceField::Vortex { .. }) { direction.perp() } else { direction };
+                direction * strength * falloff
+            },
+        }
+    }
+}
+
+
+/// Converts stiffness of the whole step into stiffness of one solver iteration,
+/// so softness doesn't depend on the iteration count.
+fn get_iteration_stiffness(stiffness: f32) -> f32 {
+    1.0 - (1.0 - stiffness.clamp(0.0, 1.0)).powf(1.0 / SOLVER_ITERATIONS as f32)
+}
+
+fn wrap_angle(angle: f32) -> f32 {
+    use std::f32::consts::{PI, TAU};
+    (angle + PI).rem_euclid(TAU) - PI
+}
 
 pub struct DistanceConstraint {
     particle_a: usize,
     particle_b: usize,
     length: f32,
+    stiffness: f32,
 }
 
+/// Keeps the bend at `particle_b` between segments c-b and b-a.
 pub struct AngleConstraint {
     particle_a: usize,
     particle_b: usize,
     particle_c: usize,
     angle: f32,
+    stiffness: f32,
 }
 
 
 pub fn init_test_simulation(sim: &mut ParticleSimulation) {
-        const SCREEN_MIDDLE: Vec2 = Vec2::new(1280.0/2.0, 720.0/2.0);
+        const WORLD_MIDDLE: Vec2 = Vec2::new(WORLD_SIZE.x/2.0, WORLD_SIZE.y/2.0);
         const DEFAULT_MASS: f32 = 1.0;
         const DYNAMIC: bool = false;
         const FIXED: bool = true;
 
-        let a = sim.new_particle(SCREEN_MIDDLE + Vec2::new(100.0, 0.0), 10.0, DEFAULT_MASS, FIXED);
-        let b = sim.new_particle(SCREEN_MIDDLE - Vec2::new(100.0, 0.0), 10.0, DEFAULT_MASS, DYNAMIC);
+        let a = sim.new_particle(WORLD_MIDDLE + Vec2::new(100.0, 0.0), 10.0, DEFAULT_MASS, FIXED);
+        let b = sim.new_particle(WORLD_MIDDLE - Vec2::new(100.0, 0.0), 10.0, DEFAULT_MASS, DYNAMIC);
         sim.new_distance_constrain(a, b, 100.0);
 
 }
@@ -85,6 +192,10 @@ pub struct ParticleSimulation {
     pub particles: Vec<PhysicsParticle>,
     distance_constrains: Vec<DistanceConstraint>,
     angle_constrains: Vec<AngleConstraint>,
+    colliders: Vec<Collider>,
+    force_fields: Vec<ForceField>,
+    /// Seconds every `physics_step` moves things along, keep it the same from step to step
+    pub timestep: f32,
 }
 
 impl ParticleSimulation {
@@ -93,12 +204,15 @@ impl ParticleSimulation {
             particles: Vec::new(),
             distance_constrains: Vec::new(),
             angle_constrains: Vec::new(),
+            colliders: Vec::new(),
+            force_fields: Vec::new(),
+            timestep: DELTA_TIME,
         }
     }
 
     pub fn physics_step(&mut self) {
 
-        for _ in 0..100 {
+        for _ in 0..SOLVER_ITERATIONS {
             //self.solve_collisions(); 
             self.solve_distance_constrains();
             self.solve_angle_constrains();
@@ -107,9 +221,13 @@ impl ParticleSimulation {
         const GRAVITY: Vec2 = Vec2::new(0.0, 0.0);
         for particle in &mut self.particles {
             particle.accelerate(GRAVITY); // Applying gravity
-            particle.physics_step();
-            const SCREEN_MIDDLE: Vec2 = Vec2::new(1280.0/2.0, 720.0/2.0);
-            //particle.constrain_circle(SCREEN_MIDDLE, 300.0);
+            particle.accelerate(Self::get_field_acceleration(&self.force_fields, particle.pos));
+            particle.physics_step(self.timestep);
+            for collider in &self.colliders {
+                particle.constrain_collider(collider);
+            }
+            const WORLD_MIDDLE: Vec2 = Vec2::new(WORLD_SIZE.x/2.0, WORLD_SIZE.y/2.0);
+            //particle.constrain_circle(WORLD_MIDDLE, 300.0);
         }
 
 
@@ -120,7 +238,7 @@ impl ParticleSimulation {
             let [a, b] = self.particles.get_many_mut([constrain.particle_a, constrain.particle_b]).unwrap();
             let dist = b.pos - a.pos;
             let ab = dist.normalize_or_zero();
-            let adjust_amount = (dist.length() - constrain.length) * ab;
+            let adjust_amount = (dist.length() - constrain.length) * ab * constrain.stiffness;
             let mass_sum = a.mass + b.mass;
             let adjust_a = adjust_amount * b.mass / mass_sum;
             let adjust_b = adjust_amount * a.mass / mass_sum;
@@ -131,12 +249,38 @@ impl ParticleSimulation {
 
     fn solve_angle_constrains(&mut self) {
         for constrain in &self.angle_constrains {
-            let init_angle = self.get_hack_angle(constrain.particle_a, constrain.particle_b);
-            let angle = init_angle + self.get_angle_between_particles(constrain.particle_a, constrain.particle_b, constrain.particle_c) / 2.0;
+            if constrain.stiffness == 0.0 { continue; }
             let [a, b, c] = self.particles.get_many_mut([constrain.particle_a, constrain.particle_b, constrain.particle_c]).unwrap();
+            let inv_mass = |p: &PhysicsParticle| if p.fixed { 0.0 } else { 1.0 / p.mass };
+            let (inv_mass_a, inv_mass_b, inv_mass_c) = (inv_mass(a), inv_mass(b), inv_mass(c));
+
+            let outgoing = a.pos - b.pos;
+            let incoming = b.pos - c.pos;
+            if outgoing == Vec2::ZERO || incoming == Vec2::ZERO { continue; }
+
+            // How the bend changes when each particle moves, they sum up to zero so momentum is kept
+            let grad_a = outgoing.perp() / outgoing.length_squared();
+            let grad_c = incoming.perp() / incoming.length_squared();
+            let grad_b = -grad_a - grad_c;
+
+            let weight = inv_mass_a * grad_a.length_squared() + inv_mass_b * grad_b.length_squared() + inv_mass_c * grad_c.length_squared();
+            if weight == 0.0 { continue; }
+
+            let error = wrap_angle(Self::get_bend_angle(a.pos, b.pos, c.pos) - constrain.angle);
+            let lambda = -error / weight * constrain.stiffness;
+            a.pos += grad_a * lambda * inv_mass_a;
+            b.pos += grad_b * lambda * inv_mass_b;
+            c.pos += grad_c * lambda * inv_mass_c;
         }
     }
 
+    /// Signed angle between segments c-b and b-a, zero when they are in line.
+    fn get_bend_angle(a: Vec2, b: Vec2, c: Vec2) -> f32 {
+        let incoming = b - c;
+        let outgoing = a - b;
+        incoming.perp_dot(outgoing).atan2(incoming.dot(outgoing))
+    }
+
     fn solve_collisions(&mut self) {
         let l = self.particles.len();
         for i in 0..l {
@@ -151,10 +295,19 @@ impl ParticleSimulation {
         }
     }
 
-    pub fn display(&mut self, graphics: &mut Graphics2D) {
+    pub fn display_colliders(&self, renderer: &mut dyn Renderer) {
+        for collider in &self.colliders {
+            collider.display(renderer);
+        }
+    }
+
+    /// Debug view, every particle and constraint on top of the colliders.
+    pub fn display(&mut self, renderer: &mut dyn Renderer) {
+        self.display_colliders(renderer);
+
         for constrain in &self.distance_constrains {
             let [a, b] = self.particles.get_many_mut([constrain.particle_a, constrain.particle_b]).unwrap();
-            Self::display_distance_constrain(a, b, graphics);
+            Self::display_distance_constrain(a, b, renderer);
         }
 
         for constrain in &self.angle_constrains {
@@ -169,21 +322,21 @@ impl ParticleSimulation {
             const LINE_COLOR: Color = Color::from_rgb(1.0, 0.0, 0.0);
             const LINE_COLOR2: Color = Color::from_rgb(0.0, 1.0, 0.0);
             const LINE_COLOR3: Color = Color::from_rgb(0.0, 0.0, 1.0);
-            graphics.draw_line::<(f32, f32), (f32, f32)>(b.pos.into(), (b.pos + Vec2::from_angle(init_angle)*100.0).into(), LINE_THICKNESS, LINE_COLOR2);
-            graphics.draw_line::<(f32, f32), (f32, f32)>(b.pos.into(), (b.pos + Vec2::from_angle(angle)*100.0).into(), LINE_THICKNESS, LINE_COLOR);
-            graphics.draw_line::<(f32, f32), (f32, f32)>(b.pos.into(), (b.pos + Vec2::from_angle(sub_angle)*100.0).into(), LINE_THICKNESS, LINE_COLOR3);
+            renderer.draw_line(b.pos, b.pos + Vec2::from_angle(init_angle)*100.0, LINE_THICKNESS, LINE_COLOR2);
+            renderer.draw_line(b.pos, b.pos + Vec2::from_angle(angle)*100.0, LINE_THICKNESS, LINE_COLOR);
+            renderer.draw_line(b.pos, b.pos + Vec2::from_angle(sub_angle)*100.0, LINE_THICKNESS, LINE_COLOR3);
         }
 
-        for particle in &self.particles {
-            particle.display(graphics);
+        for particle in self.particles.iter().filter(|particle| particle.active) {
+            particle.display(renderer);
         }
     }
 
-    fn display_distance_constrain(a: &PhysicsParticle, b: &PhysicsParticle, graphics: &mut Graphics2D){
+    fn display_distance_constrain(a: &PhysicsParticle, b: &PhysicsParticle, renderer: &mut dyn Renderer){
         const LINE_THICKNESS: f32 = 1.0;
         const LINE_COLOR: Color = Color::from_rgb(237.0/255.0, 198.0/255.0, 114.0/255.0);
 
-        graphics.draw_line::<(f32, f32), (f32, f32)>(a.pos.into(), b.pos.into(), LINE_THICKNESS, LINE_COLOR);
+        renderer.draw_line(a.pos, b.pos, LINE_THICKNESS, LINE_COLOR);
     }
 
     pub fn new_particle(&mut self, pos: Vec2, r: f32, mass: f32, fixed: bool) -> usize {
@@ -195,13 +348,87 @@ impl ParticleSimulation {
             color: Color::WHITE,
             radius: r,
             fixed,
+            damping: 0.0,
+            active: true,
         };
         self.particles.push(particle);
         self.particles.len() - 1
     }
 
+    pub fn add_collider(&mut self, collider: Collider) {
+        self.colliders.push(collider);
+    }
+
+    pub fn colliders(&self) -> &[Collider] {
+        &self.colliders
+    }
+
+    pub fn add_force_field(&mut self, field: ForceField) {
+        self.force_fields.push(field);
+    }
+
+    pub fn force_fields(&self) -> &[ForceField] {
+        &self.force_fields
+    }
+
+    /// Sum of all force fields at the point, what anything else moving through the scene should feel too.
+    pub fn get_acceleration_at(&self, point: Vec2) -> Vec2 {
+        Self::get_field_acceleration(&self.force_fields, point)
+    }
+
+    /// Drags particles along with a flowing medium, `wind` gives its velocity in pixels per second.
+    /// Call before `physics_step`, `drag` is how quickly a particle catches up, per second.
+    /// Still air doesn't hold anything back, drag fades in up to `CALM_WIND_SPEED`.
+    pub fn apply_wind(&mut self, wind: impl Fn(Vec2) -> Vec2, drag: f32) {
+        let timestep = self.timestep;
+        for particle in self.particles.iter_mut().filter(|particle| particle.active && !particle.fixed) {
+            let wind = wind(particle.pos);
+            let strength = (wind.length() / CALM_WIND_SPEED).min(1.0);
+            if strength == 0.0 {
+                continue;
+            }
+            let velocity = (particle.pos - particle.last_pos) / timestep;
+            particle.accelerate((wind - velocity) * drag * strength);
+        }
+    }
+
+    fn get_field_acceleration(fields: &[ForceField], point: Vec2) -> Vec2 {
+        fields.iter().map(|field| field.get_acceleration(point)).sum()
+    }
+
+    /// Inactive particles are kept for reuse, they don't move and aren't drawn.
+    pub fn set_active(&mut self, particle: usize, active: bool) {
+        self.particles[particle].active = active;
+    }
+
+    /// How fast the particle moved over the last step, pixels per second.
+    pub fn get_velocity(&self, particle: usize) -> Vec2 {
+        let particle = &self.particles[particle];
+        (particle.pos - particle.last_pos) / self.timestep
+    }
+
+    pub fn set_fixed(&mut self, particle: usize, fixed: bool) {
+        self.particles[particle].fixed = fixed;
+    }
+
+    pub fn set_mass(&mut self, particle: usize, mass: f32) {
+        self.particles[particle].mass = mass;
+    }
+
+    /// Moves the particle without giving it any velocity.
+    pub fn teleport(&mut self, particle: usize, pos: Vec2) {
+        let particle = &mut self.particles[particle];
+        particle.pos = pos;
+        particle.last_pos = pos;
+    }
+
+    /// Fraction of the velocity lost every step.
+    pub fn set_damping(&mut self, particle: usize, damping: f32) {
+        self.particles[particle].damping = damping.clamp(0.0, 1.0);
+    }
+
     pub fn new_distance_constrain(&mut self, particle_a: usize, particle_b: usize, length: f32) {
-        self.distance_constrains.push(DistanceConstraint{ particle_a, particle_b, length });
+        self.distance_constrains.push(DistanceConstraint{ particle_a, particle_b, length, stiffness: 1.0 });
     }
 
     pub fn get_distance_between_particles(&mut self, particle_a: usize, particle_b: usize) -> f32 {
@@ -226,19 +453,55 @@ impl ParticleSimulation {
     }
     
     pub fn new_distance_constrain_in_place(&mut self, particle_a: usize, particle_b: usize) {
+        self.new_soft_distance_constrain_in_place(particle_a, particle_b, 1.0);
+    }
+
+    /// Stiffness is in 0..1, how much of the stretch is fixed during one physics step.
+    pub fn new_soft_distance_constrain_in_place(&mut self, particle_a: usize, particle_b: usize, stiffness: f32) {
         let length = self.get_distance_between_particles(particle_a, particle_b);
-        self.distance_constrains.push( DistanceConstraint { particle_a, particle_b, length })
+        let stiffness = get_iteration_stiffness(stiffness);
+        self.distance_constrains.push( DistanceConstraint { particle_a, particle_b, length, stiffness })
     }
 
     pub fn new_angle_constrain_in_place(&mut self, particle_a: usize, particle_b: usize, particle_c: usize) {
-        let angle = self.get_angle_between_particles(particle_a, particle_b, particle_c);
-        self.angle_constrains.push( AngleConstraint { particle_a, particle_b, particle_c, angle })
+        self.new_soft_angle_constrain_in_place(particle_a, particle_b, particle_c, 1.0);
     }
 
-    pub fn select_point(&self, pos: Vec2) -> Option<usize> {
-        const EPSILON: f32 = 10.0;
+    /// Stiffness is in 0..1, how much of the bend is undone during one physics step.
+    pub fn new_soft_angle_constrain_in_place(&mut self, particle_a: usize, particle_b: usize, particle_c: usize, stiffness: f32) {
+        let (a, b, c) = (self.particles[particle_a].pos, self.particles[particle_b].pos, self.particles[particle_c].pos);
+        let angle = Self::get_bend_angle(a, b, c);
+        let stiffness = get_iteration_stiffness(stiffness);
+        self.angle_constrains.push( AngleConstraint { particle_a, particle_b, particle_c, angle, stiffness })
+    }
+
+    /// Removes every constraint linking the given particles with the rest,
+    /// so they move as a separate object. Constraints between them stay.
+    pub fn detach(&mut self, particles: &[usize]) {
+        let mut inside = vec![false; self.particles.len()];
+        for &particle in particles {
+            inside[particle] = true;
+        }
+        let crosses = |ids: &[usize]| {
+            let count = ids.iter().filter(|&&id| inside[id]).count();
+            count != 0 && count != ids.len()
+        };
+        self.distance_constrains.retain(|c| !crosses(&[c.particle_a, c.particle_b]));
+        self.angle_constrains.retain(|c| !crosses(&[c.particle_a, c.particle_b, c.particle_c]));
+    }
+
+    /// Particles of every distance and angle constraint, to check what `detach` kept.
+    #[cfg(test)]
+    pub fn get_constrained_particles(&self) -> (Vec<[usize; 2]>, Vec<[usize; 3]>) {
+        let distance = self.distance_constrains.iter().map(|c| [c.particle_a, c.particle_b]).collect();
+        let angle = self.angle_constrains.iter().map(|c| [c.particle_a, c.particle_b, c.particle_c]).collect();
+        (distance, angle)
+    }
+
+    /// First active particle within `radius` of the point, both in world units.
+    pub fn select_point(&self, pos: Vec2, radius: f32) -> Option<usize> {
         for i in 0..self.particles.len() {
-            if self.particles[i].pos.distance_squared(pos) < EPSILON {
+            if self.particles[i].active && self.particles[i].pos.distance_squared(pos) < radius * radius {
                 return Some(i)
             }
         }
@@ -246,3 +509,28 @@ impl ParticleSimulation {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Where a particle pulled down at `acc` ends up after a second of steps at `fps`.
+    fn fall_for_a_second(fps: u32, acc: Vec2) -> Vec2 {
+        let mut simulation = ParticleSimulation::new();
+        simulation.timestep = 1.0 / fps as f32;
+        let particle = simulation.new_particle(Vec2::ZERO, 1.0, 1.0, false);
+        for _ in 0..fps {
+            simulation.particles[particle].accelerate(acc);
+            simulation.physics_step();
+        }
+        simulation.particles[particle].pos
+    }
+
+    #[test]
+    fn a_second_is_a_second_at_any_timestep() {
+        let acc = Vec2::new(0.0, 100.0);
+        let (at_60, at_30) = (fall_for_a_second(60, acc), fall_for_a_second(30, acc));
+        assert!(at_60.y > 10.0);
+        assert!((at_60.y - at_30.y).abs() < at_60.y * 0.05, "{at_60} vs {at_30}");
+    }
+}